@@ -0,0 +1 @@
+ALTER TABLE backup_requests ADD COLUMN warning_count INTEGER NOT NULL DEFAULT 0;
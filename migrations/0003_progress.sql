@@ -0,0 +1 @@
+ALTER TABLE backup_requests ADD COLUMN progress TEXT;
@@ -0,0 +1,2 @@
+-- existing requests were albums and playlists backups
+ALTER TABLE backup_requests ADD COLUMN options TEXT NOT NULL DEFAULT '{"collections":["albums","playlists"]}';
@@ -0,0 +1,13 @@
+ALTER TABLE backup_requests ADD COLUMN schedule_id TEXT;
+
+CREATE TABLE backup_schedules (
+id            TEXT PRIMARY KEY,
+user_id       TEXT NOT NULL,
+refresh_token TEXT NOT NULL,
+frequency     TEXT NOT NULL,
+options       TEXT NOT NULL,
+created_at    TEXT NOT NULL,
+next_run_at   TEXT NOT NULL,
+last_error    TEXT
+)
+;
@@ -0,0 +1,10 @@
+ALTER TABLE backup_requests ADD COLUMN user_id TEXT;
+ALTER TABLE backup_requests ADD COLUMN file_size INTEGER;
+
+CREATE TABLE users (
+id            TEXT PRIMARY KEY,
+display_name  TEXT,
+created_at    TEXT NOT NULL,
+last_login_at TEXT NOT NULL
+)
+;
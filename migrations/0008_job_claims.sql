@@ -0,0 +1,5 @@
+ALTER TABLE backup_requests ADD COLUMN claimed_by TEXT;
+ALTER TABLE backup_requests ADD COLUMN claim_id TEXT;
+ALTER TABLE backup_requests ADD COLUMN lease_expires_at TEXT;
+
+CREATE INDEX backup_requests_status ON backup_requests (status, created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE backup_requests ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE backup_requests ADD COLUMN next_attempt_at TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE backup_requests ADD COLUMN started_at TEXT;
+ALTER TABLE backup_requests ADD COLUMN finished_at TEXT;
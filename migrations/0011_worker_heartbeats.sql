@@ -0,0 +1,5 @@
+CREATE TABLE worker_heartbeats (
+worker_id    TEXT PRIMARY KEY,
+last_seen_at TEXT NOT NULL
+)
+;
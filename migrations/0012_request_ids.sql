@@ -0,0 +1 @@
+ALTER TABLE backup_requests ADD COLUMN request_id TEXT;
@@ -1,8 +1,9 @@
 use crate::serialize::*;
 use crate::spotify::client::*;
+use crate::progress::Progress;
 use failure::Error;
 
-pub fn backup_albums(spotify: &dyn SpotifyClient) -> Result<Vec<Album>, Error> {
+pub fn backup_albums(spotify: &dyn SpotifyClient, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Vec<Album>, Error> {
     let mut parsed_albums = vec![];
     let mut offset = 0;
 
@@ -11,6 +12,8 @@ pub fn backup_albums(spotify: &dyn SpotifyClient) -> Result<Vec<Album>, Error> {
 
         parsed_albums.extend(albums.items);
 
+        progress(&Progress::new("albums", parsed_albums.len() as u32, albums.total))?;
+
         if albums.next.is_none() {
             break;
         } else {
@@ -26,40 +29,11 @@ mod tests {
     use super::*;
 
     use mockall::predicate::*;
-    use mockall::*;
     use rspotify::spotify::model::page::Page;
+    use crate::spotify::client::MockSpotifyClient;
     use std::iter;
-
-    mock! {
-        pub SpotifyClientM { }
-        trait SpotifyClient {
-            fn saved_albums(
-                &self,
-                limit: Option<u32>,
-                offset: Option<u32>
-            ) -> Result<Page<Album>, Error>;
-
-            fn playlists(
-                &self,
-                user_id: &str,
-                limit: Option<u32>,
-                offset: Option<u32>,
-            ) -> Result<Page<PlaylistId>, Error>;
-
-            fn playlist(
-                &self,
-                playlist_id: &PlaylistId,
-            ) -> Result<(Playlist, Page<Track>), Error>;
-
-            fn playlist_tracks(
-                &self,
-                user_id: &str,
-                playlist_id: &PlaylistId,
-                limit: Option<u32>,
-                offset: Option<u32>,
-            ) -> Result<Page<Track>, Error>;
-        }
-    }
+    use std::cell::RefCell;
+    use crate::progress::Cancelled;
 
     fn new_album() -> Album {
         let fake_artist = Artist {
@@ -92,14 +66,14 @@ mod tests {
 
     #[test]
     fn test_backup_albums() {
-        let mut mock = MockSpotifyClientM::new();
+        let mut mock = MockSpotifyClient::new();
 
         mock.expect_saved_albums()
             .with(eq(Some(50)), eq(Some(0)))
             .times(1)
             .returning(|_, _| Ok(new_page(None, None)));
 
-        let backed_up_albums = backup_albums(&mock).unwrap();
+        let backed_up_albums = backup_albums(&mock, &|_| Ok(())).unwrap();
 
         let p = new_page(None, None);
 
@@ -108,7 +82,7 @@ mod tests {
 
     #[test]
     fn test_backup_multiple_pages() {
-        let mut mock = MockSpotifyClientM::new();
+        let mut mock = MockSpotifyClient::new();
 
         mock.expect_saved_albums()
             .with(eq(Some(50)), eq(Some(0)))
@@ -120,10 +94,59 @@ mod tests {
             .times(1)
             .returning(|_, _| Ok(new_page(Some(3), None)));
 
-        let backed_up_albums = backup_albums(&mock).unwrap();
+        let backed_up_albums = backup_albums(&mock, &|_| Ok(())).unwrap();
 
         let p = new_page(Some(53), None);
 
         assert_eq!(backed_up_albums, p.items);
     }
+
+    #[test]
+    fn test_reports_progress() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_saved_albums()
+            .with(eq(Some(50)), eq(Some(0)))
+            .times(1)
+            .returning(|_, _| {
+                let mut page = new_page(Some(50), Some("next".into()));
+                page.total = 53;
+                Ok(page)
+            });
+
+        mock.expect_saved_albums()
+            .with(eq(Some(50)), eq(Some(50)))
+            .times(1)
+            .returning(|_, _| {
+                let mut page = new_page(Some(3), None);
+                page.total = 53;
+                Ok(page)
+            });
+
+        let reported = RefCell::new(vec![]);
+
+        backup_albums(&mock, &|p| {
+            reported.borrow_mut().push(p.clone());
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(reported.into_inner(), vec![
+            Progress::new("albums", 50, 53),
+            Progress::new("albums", 53, 53),
+        ]);
+    }
+
+    #[test]
+    fn test_stops_when_progress_fails() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_saved_albums()
+            .with(eq(Some(50)), eq(Some(0)))
+            .times(1)
+            .returning(|_, _| Ok(new_page(Some(50), Some("next".into()))));
+
+        let res = backup_albums(&mock, &|_| Err(Cancelled.into()));
+
+        assert!(res.unwrap_err().downcast_ref::<Cancelled>().is_some());
+    }
 }
@@ -0,0 +1,69 @@
+use crate::serialize::*;
+use crate::spotify::client::*;
+use crate::progress::Progress;
+use failure::Error;
+
+pub fn backup_followed_artists(spotify: &dyn SpotifyClient, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Vec<Artist>, Error> {
+    let mut parsed_artists = vec![];
+    let mut after = None;
+
+    loop {
+        let artists = spotify.followed_artists(Some(50), after)?;
+
+        parsed_artists.extend(artists.items);
+
+        let fetched = parsed_artists.len() as u32;
+        progress(&Progress::new("followed_artists", fetched, artists.total.unwrap_or(fetched)))?;
+
+        // spotify pages followed artists with a cursor instead of an offset
+        after = artists.cursors.after;
+
+        if artists.next.is_none() || after.is_none() {
+            break;
+        }
+    }
+
+    Ok(parsed_artists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockall::predicate::*;
+    use rspotify::spotify::model::page::{CursorBasedPage, Cursor};
+    use crate::spotify::client::MockSpotifyClient;
+
+    fn new_page(name: &str, after: Option<String>) -> CursorBasedPage<Artist> {
+        CursorBasedPage {
+            href: "https://...".into(),
+            items: vec![Artist { name: name.into() }],
+            limit: 50,
+            next: after.as_ref().map(|a| format!("https://...?after={}", a)),
+            cursors: Cursor { after },
+            total: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_backup_followed_artists_follows_cursor() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_followed_artists()
+            .with(eq(Some(50)), eq(None))
+            .times(1)
+            .returning(|_, _| Ok(new_page("Amália Rodrigues", Some("cursor-01".into()))));
+
+        mock.expect_followed_artists()
+            .with(eq(Some(50)), eq(Some("cursor-01".to_owned())))
+            .times(1)
+            .returning(|_, _| Ok(new_page("Carlos Paredes", None)));
+
+        let artists = backup_followed_artists(&mock, &|_| Ok(())).unwrap();
+
+        assert_eq!(artists, vec![
+            Artist { name: "Amália Rodrigues".into() },
+            Artist { name: "Carlos Paredes".into() },
+        ]);
+    }
+}
@@ -1,12 +1,33 @@
 use std::env;
+use std::path::PathBuf;
 use spotify_backup::config::Config;
 
 pub fn main() -> (){
-    pretty_env_logger::init();
+    let mut args: Vec<String> = env::args().collect();
 
-    let args: Vec<String> = env::args().collect();
+    // `--config <path>` can be passed before or after the command
+    let config_path = match args.iter().position(|a| a == "--config") {
+        Some(idx) if idx + 1 < args.len() => {
+            let path = args.remove(idx + 1);
+            args.remove(idx);
+            Some(PathBuf::from(path))
+        },
+        Some(_) => {
+            eprintln!("--config needs a path");
+            std::process::exit(1)
+        },
+        None => None,
+    };
 
-    let config = Config::load().expect("could not load config");
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Could not load config: {}", err);
+            std::process::exit(1)
+        }
+    };
+
+    spotify_backup::logging::init(config.log_format);
 
     log::debug!("using config: {}", config);
 
@@ -14,7 +35,13 @@ pub fn main() -> (){
         spotify_backup::server::server(config).unwrap();
     } else if args.len() == 2 && args[1] == "daemon" {
         spotify_backup::server::daemon::daemon(config);
+    } else if args.len() == 2 && args[1] == "migrate" {
+        spotify_backup::server::migrate(config, false).unwrap();
+    } else if args.len() == 3 && args[1] == "migrate" && args[2] == "status" {
+        spotify_backup::server::migrate(config, true).unwrap();
+    } else if args.len() == 2 && args[1] == "rotate-keys" {
+        spotify_backup::server::rotate_keys(config).unwrap();
     }  else {
-        spotify_backup::cli::cli();
+        spotify_backup::cli::cli(&config);
     }
 }
@@ -1,25 +1,130 @@
 use rspotify::spotify::util::get_token;
 use crate::spotify::*;
 use crate::backup_fn::DefaultBackup;
+use crate::options::BackupOptions;
+use crate::config::Config;
 use std::path::PathBuf;
+use std::env;
+use rspotify::spotify::oauth2::{SpotifyOAuth, TokenInfo};
+use failure::Error;
 
-pub fn cli() {
-    // Needs env file
-    let mut oauth = build_spotify_oauth("http://localhost:8000", PathBuf::from("./spotify_token_cache.json"));
+// The token saved by the last authorization, refreshed if it expired. `None` if there is none
+// or it does not have every scope, the user is then asked to authorize again
+fn cached_token_pkce(oauth: &SpotifyOAuth) -> Option<TokenInfo> {
+    let cached = std::fs::read_to_string(&oauth.cache_path).ok()?;
+    let token: TokenInfo = serde_json::from_str(&cached).ok()?;
 
-    match get_token(&mut oauth) {
+    if !oauth.scope.split_whitespace().all(|s| token.scope.split_whitespace().any(|t| t == s)) {
+        return None;
+    }
+
+    // refreshed a minute early so it does not expire while the backup starts
+    if token.expires_at.map(|at| at > time::get_time().sec + 60).unwrap_or(false) {
+        return Some(token);
+    }
+
+    let refresh_token = token.refresh_token?;
+
+    match pkce::refresh_access_token(oauth, &refresh_token) {
+        Ok(mut refreshed) => {
+            // spotify only sends a refresh token when it replaces the old one
+            refreshed.refresh_token = refreshed.refresh_token.or(Some(refresh_token));
+            Some(refreshed)
+        },
+        Err(err) => {
+            log::warn!("Could not refresh cached spotify token: {}", err);
+            None
+        }
+    }
+}
+
+fn save_token(oauth: &SpotifyOAuth, token: &TokenInfo) {
+    let saved = serde_json::to_string(token).map_err(Error::from)
+        .and_then(|json| std::fs::write(&oauth.cache_path, json).map_err(Error::from));
+
+    if let Err(err) = saved {
+        log::warn!("Could not save spotify token to {:?}: {}", oauth.cache_path, err)
+    }
+}
+
+// Uses the cached token if possible, otherwise authorizes the user and caches the new token
+fn get_token_pkce(oauth: &SpotifyOAuth) -> Result<TokenInfo, Error> {
+    let token = match cached_token_pkce(oauth) {
+        Some(token) => token,
+        None => authorize_pkce(oauth)?,
+    };
+
+    save_token(oauth, &token);
+    Ok(token)
+}
+
+// Like rspotify's `get_token`, the user opens the url and pastes back the url spotify redirected to
+fn authorize_pkce(oauth: &SpotifyOAuth) -> Result<TokenInfo, Error> {
+    let auth = build_user_redirect_uri(oauth, &oauth.scope, &())?;
+
+    eprintln!("Open this url in your browser and allow access:\n\n{}\n", auth.url);
+    eprintln!("Then paste the url you were redirected to:");
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    let redirected = reqwest::Url::parse(input.trim())?;
+    let param = |name: &str| redirected.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string());
+
+    if param("state").as_ref() != Some(&auth.state) {
+        failure::bail!("the pasted url does not belong to this authorization")
+    }
+
+    if let Some(error) = param("error") {
+        failure::bail!("spotify authorization failed: {}", error)
+    }
+
+    let code = param("code").ok_or_else(|| failure::format_err!("the pasted url does not have a code"))?;
+    get_access_token(oauth, &code, auth.verifier.as_ref().map(|v| v.as_str()))
+}
+
+pub fn cli(config: &Config) {
+    // Comma separated list of collections to backup, eg. `albums,playlists,liked_songs,followed_artists`
+    let options = match env::var("BACKUP_COLLECTIONS") {
+        Ok(collections) => BackupOptions::parse_collections(&collections).expect("Could not parse BACKUP_COLLECTIONS"),
+        Err(_) => BackupOptions::default(),
+    };
+
+    let mut oauth = build_spotify_oauth(config.client_id(), config.client_secret(), "http://localhost:8000", PathBuf::from("./spotify_token_cache.json"), &options.scope());
+
+    let token = if uses_pkce(&oauth) {
+        get_token_pkce(&oauth).map_err(|err| log::error!("{}", err)).ok()
+    } else {
+        get_token(&mut oauth)
+    };
+
+    match token {
         Some(token_info) => {
-            let backup = DefaultBackup::run_backup(token_info).unwrap();
+            // progress is also reported between pages without new items, eg. between playlist tracks
+            let last_progress = std::cell::RefCell::new(None);
+
+            let backup = DefaultBackup::run_backup(token_info, &options, &|progress| {
+                if last_progress.replace(Some(progress.clone())).as_ref() != Some(progress) {
+                    eprintln!("Fetched {}/{} {}", progress.fetched, progress.total, progress.collection);
+                }
+                Ok(())
+            }).unwrap();
 
             let serialized = serde_json::to_string_pretty(&backup).unwrap();
 
             println!("{}", serialized);
 
             log::info!(
-                "Saved {} albums, {} playlists",
+                "Saved {} albums, {} playlists, {} liked songs, {} followed artists",
                 backup.albums.len(),
-                backup.playlists.len()
+                backup.playlists.len(),
+                backup.liked_songs.len(),
+                backup.followed_artists.len()
             );
+
+            for err in backup.errors.iter() {
+                log::warn!("Could not backup {} {}: {}", err.collection, err.id, err.message);
+            }
         }
         None => log::error!("auth failed"),
     };
@@ -1,32 +1,228 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use actix_web::http::Uri;
 use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use std::env;
 use std::fmt::Display;
 use failure::_core::fmt::{Formatter, Error};
 use std::io::Read;
+use std::collections::HashMap;
+use std::str::FromStr;
+use crate::logging::LogFormat;
 
+// Built from `Config::default`, then the config file, then env variables, see `Config::load`
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     data_dir: PathBuf,
     pub worker_count: u32,
     base_uri: ConfigUri,
+    // spotify app credentials, from the developer dashboard. Users are authorized with
+    // PKCE if there is no secret, so it does not have to be given to everyone running the cli
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    // addresses the http server listens on, `unix:<path>` for unix sockets
+    pub listen: Vec<String>,
+    // tcp addresses are served over https if set
+    tls: Option<TlsConfig>,
+    // threads serving http requests, the number of cpus if not set
+    pub http_workers: Option<usize>,
+    // templates and static files are embedded in the binary, files in these
+    // directories are used instead of the embedded ones with the same name
+    pub templates_dir: Option<PathBuf>,
+    pub static_dir: Option<PathBuf>,
+    // base64 encoded 32 byte key, recurring backups are disabled if not set
+    token_key: Option<String>,
+    // saved with every encrypted token, change it when changing `token_key`
+    token_key_id: String,
+    // previous keys by id, only used to decrypt tokens until `rotate-keys` runs
+    old_token_keys: HashMap<String, String>,
+    pub scheduled_backups_kept: u32,
+    // base64 encoded key of at least 32 bytes to sign session cookies, a random one
+    // is generated on startup if not set which logs everybody out on restarts
+    session_key: Option<String>,
+    // pending backups not started after this long are set to error
+    pub pending_timeout_minutes: u32,
+    // a worker must renew its claim on a running backup within this long, otherwise
+    // the backup is claimed again by another worker, eg. after the daemon crashed
+    pub job_lease_seconds: u32,
+    // backups failing because of spotify or network errors are retried until they
+    // were attempted this many times, waiting `retry_backoff_seconds`, doubled after every attempt
+    pub max_attempts: u32,
+    pub retry_backoff_seconds: u32,
+    // how long a backup is available for download, unless the user picks a different one
+    retention_hours: u32,
+    // the longest retention users can pick
+    max_retention_hours: u32,
+    // max number of expired backups deleted every few seconds
+    pub sweep_batch_size: u32,
+    // backups are saved to `downloads_path` if not set
+    s3: Option<S3Config>,
+    // spotify user ids allowed to use `/admin`, the admin section is disabled if empty
+    pub admin_users: Vec<String>,
+    // address the daemon serves `/metrics` on, eg. `0.0.0.0:9100`. The server always serves them
+    pub daemon_metrics_addr: Option<String>,
+    // `json` logs every line as a json object, with the backup, user and http request it belongs to
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // redirect downloads to a presigned url instead of streaming them through the server
+    #[serde(default)]
+    pub presigned_downloads: bool,
+}
+
+// PEM files, the key can be PKCS8 or RSA
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+fn default_s3_region() -> String { "us-east-1".to_owned() }
+
+impl S3Config {
+    fn from_env(var: &dyn Fn(&str) -> Option<String>) -> Result<Option<S3Config>, failure::Error> {
+        let bucket = match var("S3_BUCKET") {
+            Some(bucket) => bucket,
+            None => return Ok(None),
+        };
+        let required = |name: &str| var(name).ok_or_else(|| failure::format_err!("{} is required when S3_BUCKET is set", name));
+
+        Ok(Some(S3Config {
+            endpoint: required("S3_ENDPOINT")?,
+            bucket,
+            region: var("S3_REGION").unwrap_or_else(default_s3_region),
+            access_key: required("S3_ACCESS_KEY")?,
+            secret_key: required("S3_SECRET_KEY")?,
+            presigned_downloads: var("S3_PRESIGNED_DOWNLOADS").map(|v| v == "true" || v == "1").unwrap_or(false),
+        }))
+    }
+}
+
+fn default_token_key_id() -> String { "default".to_owned() }
+
+fn default_scheduled_backups_kept() -> u32 { 10 }
+
+fn default_pending_timeout_minutes() -> u32 { 60 }
+
+fn default_retention_hours() -> u32 { 1 }
+
+fn default_max_retention_hours() -> u32 { 24 }
+
+fn default_sweep_batch_size() -> u32 { 5 }
+
+fn default_job_lease_seconds() -> u32 { 300 }
+
+fn default_max_attempts() -> u32 { 3 }
+
+fn default_retry_backoff_seconds() -> u32 { 60 }
+
+// Token keys are base64 encoded AES-256 keys, the server builds a `TokenKey` from them
+const TOKEN_KEY_LEN: usize = 32;
+
+fn check_token_key(encoded: &str) -> Result<(), String> {
+    match base64::decode(encoded.trim()) {
+        Ok(key) if key.len() == TOKEN_KEY_LEN => Ok(()),
+        Ok(key) => Err(format!("token key must be {} bytes, got {}", TOKEN_KEY_LEN, key.len())),
+        Err(err) => Err(format!("{}", err)),
+    }
+}
+
+// Retention users can choose from, in hours
+const RETENTION_CHOICES: [u32; 5] = [1, 6, 24, 72, 168];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RetentionLimits {
+    pub default_hours: u32,
+    pub max_hours: u32,
+}
+
+impl RetentionLimits {
+    // `None` when the user asked for more than allowed
+    pub fn hours(&self, requested: Option<u32>) -> Option<u32> {
+        match requested {
+            None => Some(self.default_hours),
+            Some(h) if h > 0 && h <= self.max_hours => Some(h),
+            Some(_) => None,
+        }
+    }
+
+    pub fn choices(&self) -> Vec<u32> {
+        let mut choices: Vec<u32> = RETENTION_CHOICES.iter().cloned().filter(|h| *h <= self.max_hours).collect();
+        choices.push(self.default_hours);
+        choices.sort();
+        choices.dedup();
+        choices
+    }
+}
+
+// Parses the env variable `name` if it is set
+fn env_parse<T: FromStr>(var: &dyn Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, failure::Error> where T::Err: Display {
+    match var(name) {
+        Some(value) => value.parse::<T>()
+            .map(Some)
+            .map_err(|err| failure::format_err!("Could not parse {}={}: {}", name, value, err)),
+        None => Ok(None),
+    }
+}
+
+// Comma separated values, empty ones are skipped
+fn env_list(var: &dyn Fn(&str) -> Option<String>, name: &str) -> Option<Vec<String>> {
+    var(name).map(|values| {
+        values.split(',')
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty())
+            .collect()
+    })
 }
 
 #[derive(Debug)]
 struct ConfigUri(Uri);
 
-
 impl Default for Config {
     fn default() -> Self {
-        let data_dir = PathBuf::from(env::var("DATA_DIR").ok().unwrap_or("./data".to_owned()));
-        let uri: Uri = env::var("BASE_URL").ok().unwrap_or("http://localhost:8000/".into()).parse::<Uri>().expect("Could not parse BASE_URL");
-        let worker_count =  env::var("WORKER_COUNT").unwrap_or("1".into()).parse::<u32>().expect("Could not parse WORKER_COUNT");
-
         Config {
-            data_dir,
-            worker_count,
-            base_uri: ConfigUri(uri)
+            data_dir: PathBuf::from("./data"),
+            worker_count: 1,
+            base_uri: ConfigUri(Uri::from_static("http://localhost:8000/")),
+            client_id: None,
+            client_secret: None,
+            listen: vec!["0.0.0.0:8000".to_owned()],
+            tls: None,
+            http_workers: None,
+            templates_dir: None,
+            static_dir: None,
+            token_key: None,
+            token_key_id: default_token_key_id(),
+            old_token_keys: HashMap::new(),
+            scheduled_backups_kept: default_scheduled_backups_kept(),
+            session_key: None,
+            pending_timeout_minutes: default_pending_timeout_minutes(),
+            job_lease_seconds: default_job_lease_seconds(),
+            max_attempts: default_max_attempts(),
+            retry_backoff_seconds: default_retry_backoff_seconds(),
+            retention_hours: default_retention_hours(),
+            max_retention_hours: default_max_retention_hours(),
+            sweep_batch_size: default_sweep_batch_size(),
+            s3: None,
+            admin_users: vec![],
+            daemon_metrics_addr: None,
+            log_format: LogFormat::default(),
         }
     }
 }
@@ -48,23 +244,192 @@ impl<'de> Deserialize<'de> for ConfigUri {
     }
 }
 
+// Used when neither `--config` nor `SPOTIFY_BACKUP_CONFIG` are set, skipped if it does not exist
+const DEFAULT_CONFIG_PATH: &str = "./spotify-backup.toml";
+
 impl Config {
-    pub fn load() -> Result<Config, failure::Error> {
-        let config_path = PathBuf::from("./spotify-backup.toml");
+    // `path` comes from `--config`. Env variables override what is set in the file
+    pub fn load(path: Option<PathBuf>) -> Result<Config, failure::Error> {
+        let var = |name: &str| env::var(name).ok();
+        let path = path.or_else(|| var("SPOTIFY_BACKUP_CONFIG").map(PathBuf::from));
+
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None => {
+                let path = PathBuf::from(DEFAULT_CONFIG_PATH);
+                if path.exists() { Config::from_file(&path)? } else { Config::default() }
+            }
+        };
+
+        config.apply_env(&var)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, failure::Error> {
+        let mut c = String::new();
+        std::fs::File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut c))
+            .map_err(|err| failure::format_err!("Could not read config file {:?}: {}", path, err))?;
+
+        toml::from_str(&c).map_err(|err| failure::format_err!("Could not parse config file {:?}: {}", path, err))
+    }
+
+    fn apply_env(&mut self, var: &dyn Fn(&str) -> Option<String>) -> Result<(), failure::Error> {
+        if let Some(v) = var("DATA_DIR") { self.data_dir = PathBuf::from(v) }
+        if let Some(v) = env_parse(var, "WORKER_COUNT")? { self.worker_count = v }
+        if let Some(v) = env_parse(var, "BASE_URL")? { self.base_uri = ConfigUri(v) }
+        if let Some(v) = var("CLIENT_ID") { self.client_id = Some(v) }
+        if let Some(v) = var("CLIENT_SECRET") { self.client_secret = Some(v) }
+        if let Some(v) = env_list(var, "LISTEN") { self.listen = v }
+        match (var("TLS_CERT_PATH"), var("TLS_KEY_PATH")) {
+            (Some(cert), Some(key)) => self.tls = Some(TlsConfig { cert_path: PathBuf::from(cert), key_path: PathBuf::from(key) }),
+            (None, None) => (),
+            _ => failure::bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+        }
+        if let Some(v) = env_parse(var, "HTTP_WORKERS")? { self.http_workers = Some(v) }
+        if let Some(v) = var("TEMPLATES_DIR") { self.templates_dir = Some(PathBuf::from(v)) }
+        if let Some(v) = var("STATIC_DIR") { self.static_dir = Some(PathBuf::from(v)) }
+        if let Some(v) = var("TOKEN_KEY") { self.token_key = Some(v) }
+        if let Some(v) = var("TOKEN_KEY_ID") { self.token_key_id = v }
+        // comma separated `<id>:<key>` pairs
+        if let Some(keys) = env_list(var, "OLD_TOKEN_KEYS") {
+            self.old_token_keys = keys.iter().map(|k| {
+                let mut parts = k.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(id), Some(key)) => Ok((id.to_owned(), key.to_owned())),
+                    _ => Err(failure::format_err!("Could not parse OLD_TOKEN_KEYS, expected <id>:<key> pairs")),
+                }
+            }).collect::<Result<_, _>>()?;
+        }
+        if let Some(v) = env_parse(var, "SCHEDULED_BACKUPS_KEPT")? { self.scheduled_backups_kept = v }
+        if let Some(v) = var("SESSION_KEY") { self.session_key = Some(v) }
+        if let Some(v) = env_parse(var, "PENDING_TIMEOUT_MINUTES")? { self.pending_timeout_minutes = v }
+        if let Some(v) = env_parse(var, "JOB_LEASE_SECONDS")? { self.job_lease_seconds = v }
+        if let Some(v) = env_parse(var, "MAX_ATTEMPTS")? { self.max_attempts = v }
+        if let Some(v) = env_parse(var, "RETRY_BACKOFF_SECONDS")? { self.retry_backoff_seconds = v }
+        if let Some(v) = env_parse(var, "RETENTION_HOURS")? { self.retention_hours = v }
+        if let Some(v) = env_parse(var, "MAX_RETENTION_HOURS")? { self.max_retention_hours = v }
+        if let Some(v) = env_parse(var, "SWEEP_BATCH_SIZE")? { self.sweep_batch_size = v }
+        if let Some(v) = S3Config::from_env(var)? { self.s3 = Some(v) }
+        // comma separated spotify user ids
+        if let Some(v) = env_list(var, "ADMIN_USERS") { self.admin_users = v }
+        if let Some(v) = var("DAEMON_METRICS_ADDR") { self.daemon_metrics_addr = Some(v) }
+        if let Some(v) = env_parse(var, "LOG_FORMAT")? { self.log_format = v }
+        Ok(())
+    }
+
+    // Reports every problem at once
+    fn validate(&self) -> Result<(), failure::Error> {
+        let mut errors = vec![];
+
+        if self.client_id.as_ref().map(|id| id.is_empty()).unwrap_or(true) {
+            errors.push("client_id is required".to_owned());
+        }
+        if self.base_uri.0.scheme_str().is_none() || self.base_uri.0.host().is_none() {
+            errors.push(format!("base_uri must be an absolute url, got {}", self.base_uri.0));
+        }
+        if self.listen.is_empty() {
+            errors.push("listen must have at least one address".to_owned());
+        }
+        if self.listen_addrs().iter().any(|a| *a == ListenAddr::Unix(PathBuf::new())) {
+            errors.push("listen has a unix socket without a path".to_owned());
+        }
+        if self.http_workers == Some(0) {
+            errors.push("http_workers must be greater than 0".to_owned());
+        }
+        if let Some(key) = &self.session_key {
+            match base64::decode(key) {
+                Ok(key) if key.len() >= 32 => (),
+                Ok(_) => errors.push("session_key must be at least 32 bytes".to_owned()),
+                Err(err) => errors.push(format!("session_key is not valid base64: {}", err)),
+            }
+        }
+
+        if let Some(key) = &self.token_key {
+            if let Err(err) = check_token_key(key) {
+                errors.push(format!("token_key is invalid: {}", err));
+            }
+        }
+
+        // Key ids are saved in the `v1:<id>:...` envelope of every encrypted token
+        if self.token_key_id.contains(':') {
+            errors.push(format!("token_key_id must not contain ':', got {:?}", self.token_key_id));
+        }
+
+        let mut old_key_ids: Vec<&String> = self.old_token_keys.keys().collect();
+        old_key_ids.sort();
 
-        match std::fs::File::open(config_path) {
-            Ok(mut cfg) => {
-                let mut c = String::new();
-                cfg.read_to_string(&mut c)?;
-                toml::from_str(&c).map_err(failure::Error::from)
-            },
-            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-                Ok(Config::default())
-            },
-            Err(err) => Err(err.into())
+        for id in old_key_ids {
+            if id.contains(':') {
+                errors.push(format!("old_token_keys id must not contain ':', got {:?}", id));
+            }
+            if let Err(err) = check_token_key(&self.old_token_keys[id]) {
+                errors.push(format!("old_token_keys.{} is invalid: {}", id, err));
+            }
         }
+
+        if let Some(s3) = &self.s3 {
+            match s3.endpoint.parse::<Uri>() {
+                Ok(uri) if uri.scheme_str().is_some() && uri.host().is_some() => (),
+                _ => errors.push(format!("s3.endpoint must be an absolute url, got {}", s3.endpoint)),
+            }
+
+            for (name, value) in [("bucket", &s3.bucket), ("region", &s3.region), ("access_key", &s3.access_key), ("secret_key", &s3.secret_key)].iter() {
+                if value.is_empty() {
+                    errors.push(format!("s3.{} is required", name));
+                }
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            for (name, path) in [("cert_path", &tls.cert_path), ("key_path", &tls.key_path)].iter() {
+                if !path.is_file() {
+                    errors.push(format!("tls.{} {:?} is not a file", name, path));
+                }
+            }
+        }
+
+        let positive = [
+            ("worker_count", self.worker_count),
+            ("scheduled_backups_kept", self.scheduled_backups_kept),
+            ("pending_timeout_minutes", self.pending_timeout_minutes),
+            ("job_lease_seconds", self.job_lease_seconds),
+            ("max_attempts", self.max_attempts),
+            ("retention_hours", self.retention_hours),
+            ("max_retention_hours", self.max_retention_hours),
+            ("sweep_batch_size", self.sweep_batch_size),
+        ];
+
+        for (name, value) in positive.iter() {
+            if *value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            failure::bail!("Invalid config: {}", errors.join(", "))
+        }
+    }
+
+    pub fn listen_addrs(&self) -> Vec<ListenAddr> {
+        self.listen.iter().map(|addr| {
+            if addr.starts_with("unix:") {
+                ListenAddr::Unix(PathBuf::from(&addr["unix:".len()..]))
+            } else {
+                ListenAddr::Tcp(addr.clone())
+            }
+        }).collect()
     }
 
+    pub fn tls(&self) -> Option<&TlsConfig> { self.tls.as_ref() }
+
+    pub fn client_id(&self) -> &str { self.client_id.as_ref().map(|id| id.as_str()).unwrap_or_default() }
+
+    pub fn client_secret(&self) -> Option<&str> { self.client_secret.as_ref().map(|s| s.as_str()).filter(|s| !s.is_empty()) }
+
     pub fn token_cache_path(&self) -> PathBuf { self.data_dir.join(".spotify_token_cache.json") }
 
     pub fn downloads_path(&self) -> PathBuf { self.data_dir.join("downloads") }
@@ -72,17 +437,158 @@ impl Config {
     pub fn db_path(&self) -> PathBuf { self.data_dir.join("backup-requests.db") }
 
     pub fn base_uri(&self) ->  Uri { self.base_uri.0.clone() }
+
+    pub fn token_key(&self) -> Option<&str> { self.token_key.as_ref().map(|k| k.as_str()) }
+
+    pub fn token_key_id(&self) -> &str { &self.token_key_id }
+
+    pub fn old_token_keys(&self) -> &HashMap<String, String> { &self.old_token_keys }
+
+    pub fn session_key(&self) -> Option<&str> { self.session_key.as_ref().map(|k| k.as_str()) }
+
+    pub fn s3(&self) -> Option<&S3Config> { self.s3.as_ref() }
+
+    pub fn pending_timeout(&self) -> time::Duration { time::Duration::minutes(self.pending_timeout_minutes as i64) }
+
+    pub fn job_lease(&self) -> time::Duration { time::Duration::seconds(self.job_lease_seconds as i64) }
+
+    pub fn retry_backoff(&self) -> time::Duration { time::Duration::seconds(self.retry_backoff_seconds as i64) }
+
+    pub fn retention_limits(&self) -> RetentionLimits {
+        RetentionLimits {
+            default_hours: self.retention_hours.min(self.max_retention_hours),
+            max_hours: self.max_retention_hours,
+        }
+    }
 }
 
 impl Display for Config {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "data_dir={:?}, worker_count={}, token_cache_path={:?}, downloads_path={:?}, db_path={:?}, base_uri={}",
+        write!(f, "data_dir={:?}, worker_count={}, token_cache_path={:?}, downloads_path={:?}, db_path={:?}, base_uri={}, client_id={}, client_secret={}, listen={:?}, tls={}, http_workers={:?}, templates_dir={:?}, static_dir={:?}, token_key={}, token_key_id={}, old_token_keys={:?}, scheduled_backups_kept={}, session_key={}, pending_timeout_minutes={}, job_lease_seconds={}, max_attempts={}, retry_backoff_seconds={}, retention_hours={}, max_retention_hours={}, sweep_batch_size={}, s3={}, admin_users={:?}, daemon_metrics_addr={:?}, log_format={:?}",
             self.data_dir,
             self.worker_count,
             self.token_cache_path(),
             self.downloads_path(),
             self.db_path(),
-            self.base_uri()
+            self.base_uri(),
+            self.client_id.as_ref().map(|_| "<set>").unwrap_or("<not set>"),
+            self.client_secret.as_ref().map(|_| "<set>").unwrap_or("<not set>"),
+            self.listen,
+            self.tls.as_ref().map(|tls| format!("{:?}", tls.cert_path)).unwrap_or("<not set>".to_owned()),
+            self.http_workers,
+            self.templates_dir,
+            self.static_dir,
+            self.token_key.as_ref().map(|_| "<set>").unwrap_or("<not set>"),
+            self.token_key_id,
+            self.old_token_keys.keys().collect::<Vec<_>>(),
+            self.scheduled_backups_kept,
+            self.session_key.as_ref().map(|_| "<set>").unwrap_or("<not set>"),
+            self.pending_timeout_minutes,
+            self.job_lease_seconds,
+            self.max_attempts,
+            self.retry_backoff_seconds,
+            self.retention_hours,
+            self.max_retention_hours,
+            self.sweep_batch_size,
+            self.s3.as_ref().map(|s3| format!("{}/{}", s3.endpoint, s3.bucket)).unwrap_or("<not set>".to_owned()),
+            self.admin_users,
+            self.daemon_metrics_addr,
+            self.log_format
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+    }
+
+    #[test]
+    fn test_env_overrides_file() -> Result<(), failure::Error> {
+        let mut config: Config = toml::from_str(r#"
+            client_id = "file-id"
+            client_secret = "file-secret"
+            worker_count = 4
+            retention_hours = 6
+        "#)?;
+
+        config.apply_env(&env(&[("WORKER_COUNT", "2"), ("LISTEN", "127.0.0.1:8000, 127.0.0.1:8001"), ("ADMIN_USERS", "a,b")]))?;
+        config.validate()?;
+
+        assert_eq!(config.client_id(), "file-id");
+        assert_eq!(config.worker_count, 2);
+        assert_eq!(config.retention_hours, 6);
+        assert_eq!(config.max_attempts, default_max_attempts());
+        assert_eq!(config.listen, vec!["127.0.0.1:8000", "127.0.0.1:8001"]);
+        assert_eq!(config.admin_users, vec!["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parses_listen_addrs() -> Result<(), failure::Error> {
+        let mut config = Config::default();
+        config.apply_env(&env(&[("LISTEN", "0.0.0.0:8443,unix:/run/spotify-backup.sock"), ("HTTP_WORKERS", "2"),
+                                ("TLS_CERT_PATH", "cert.pem"), ("TLS_KEY_PATH", "key.pem")]))?;
+
+        assert_eq!(config.listen_addrs(), vec![ListenAddr::Tcp("0.0.0.0:8443".to_owned()), ListenAddr::Unix(PathBuf::from("/run/spotify-backup.sock"))]);
+        assert_eq!(config.http_workers, Some(2));
+        assert_eq!(config.tls().map(|tls| tls.key_path.clone()), Some(PathBuf::from("key.pem")));
+
+        assert!(Config::default().apply_env(&env(&[("TLS_CERT_PATH", "cert.pem")])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_env_is_an_error() {
+        let err = Config::default().apply_env(&env(&[("WORKER_COUNT", "many")])).unwrap_err();
+        assert!(format!("{}", err).starts_with("Could not parse WORKER_COUNT=many"));
+
+        assert!(Config::default().apply_env(&env(&[("LOG_FORMAT", "xml")])).is_err());
+        assert!(Config::default().apply_env(&env(&[("S3_BUCKET", "backups")])).is_err());
+    }
+
+    #[test]
+    fn test_reports_every_validation_error() {
+        let mut config = Config::default();
+        config.worker_count = 0;
+        config.session_key = Some(base64::encode(b"short"));
+
+        let err = format!("{}", config.validate().unwrap_err());
+
+        assert_eq!(err, "Invalid config: client_id is required, \
+                         session_key must be at least 32 bytes, worker_count must be greater than 0");
+    }
+
+    #[test]
+    fn test_validates_keys_s3_and_tls() -> Result<(), failure::Error> {
+        let mut config = Config::default();
+        config.client_id = Some("id".to_owned());
+        config.token_key = Some(base64::encode(b"short"));
+        config.token_key_id = "2020:01".to_owned();
+        config.old_token_keys.insert("old".to_owned(), "not base64!".to_owned());
+        config.old_token_keys.insert("old:1".to_owned(), base64::encode(&[1u8; 32]));
+        config.apply_env(&env(&[("S3_BUCKET", "backups"), ("S3_ENDPOINT", "minio:9000"), ("S3_ACCESS_KEY", "a"), ("S3_SECRET_KEY", ""),
+                                ("TLS_CERT_PATH", "/does/not/exist/cert.pem"), ("TLS_KEY_PATH", "/does/not/exist/key.pem")]))?;
+
+        let err = format!("{}", config.validate().unwrap_err());
+
+        assert!(err.contains("token_key is invalid: token key must be 32 bytes, got 5"));
+        assert!(err.contains("old_token_keys.old is invalid"));
+        assert!(err.contains(r#"token_key_id must not contain ':', got "2020:01""#));
+        assert!(err.contains(r#"old_token_keys id must not contain ':', got "old:1""#));
+        assert!(err.contains("s3.endpoint must be an absolute url, got minio:9000"));
+        assert!(err.contains("s3.secret_key is required"));
+        assert!(err.contains(r#"tls.cert_path "/does/not/exist/cert.pem" is not a file"#));
+        assert!(err.contains(r#"tls.key_path "/does/not/exist/key.pem" is not a file"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_fields_are_an_error() {
+        assert!(toml::from_str::<Config>("retention_hour = 6").is_err());
+        assert!(toml::from_str::<Config>("[s3]\nendpoint = \"http://minio:9000\"\nbucket = \"b\"\naccess_key = \"a\"\nsecret_key = \"s\"\npresigned = true").is_err());
+    }
+}
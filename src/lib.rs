@@ -3,54 +3,86 @@ use failure::Error;
 
 mod playlists;
 mod albums;
+mod tracks;
+mod artists;
 mod serialize;
 mod spotify;
+mod metrics;
+
+pub mod progress;
+pub mod options;
 
 pub mod server;
 pub mod cli;
 pub mod config;
+pub mod logging;
 
 pub mod backup_fn {
     use super::*;
-    use rspotify::spotify::oauth2::{SpotifyClientCredentials, TokenInfo};
+    use rspotify::spotify::oauth2::TokenInfo;
     use crate::serialize::*;
+    use crate::progress::Progress;
+    use crate::options::{BackupOptions, Collection};
 
     pub trait BackupFn {
-        fn apply(&self, token_info: TokenInfo) -> Result<Backup, Error>;
+        fn apply(&self, token_info: TokenInfo, options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error>;
     }
 
     pub struct DefaultBackup;
 
     impl DefaultBackup {
-        pub fn run_backup(token_info: TokenInfo) -> Result<Backup, Error> {
-            let client_credential = SpotifyClientCredentials::default()
-                .token_info(token_info)
-                .build();
-
-            let spotify = Spotify::default()
-                .client_credentials_manager(client_credential)
-                .build();
+        pub fn run_backup(token_info: TokenInfo, options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            let spotify = spotify::build_spotify_client(token_info);
 
-            let user_id = spotify.me()?.id;
+            let me = spotify.me().map_err(Error::from);
+            metrics::observe_api_call("me", &me);
+            let user_id = me?.id;
 
-            Self::full_backup(&user_id, &spotify)
+            Self::full_backup(&user_id, &spotify, options, progress)
         }
 
-        pub fn full_backup(user_id: &str, spotify: &Spotify) -> Result<Backup, Error> {
-            let albums = albums::backup_albums(spotify)?;
+        // Collections not included in `options` are left empty
+        pub fn full_backup(user_id: &str, spotify: &Spotify, options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            let mut errors = vec![];
+
+            let albums = if options.includes(Collection::Albums) {
+                albums::backup_albums(spotify, progress)?
+            } else {
+                vec![]
+            };
+
+            let playlists = if options.includes(Collection::Playlists) {
+                playlists::backup_playlists(&user_id, spotify, &options.playlist_filter, &mut errors, progress)?
+            } else {
+                vec![]
+            };
+
+            let liked_songs = if options.includes(Collection::LikedSongs) {
+                tracks::backup_liked_songs(spotify, progress)?
+            } else {
+                vec![]
+            };
 
-            let playlists = playlists::backup_playlists(&user_id, spotify)?;
+            let followed_artists = if options.includes(Collection::FollowedArtists) {
+                artists::backup_followed_artists(spotify, progress)?
+            } else {
+                vec![]
+            };
 
             Ok(Backup {
+                collections: options.collections.clone(),
                 albums,
                 playlists,
+                liked_songs,
+                followed_artists,
+                errors,
             })
         }
     }
 
     impl BackupFn for DefaultBackup {
-        fn apply(&self, token_info: TokenInfo) -> Result<Backup, Error> {
-            DefaultBackup::run_backup(token_info)
+        fn apply(&self, token_info: TokenInfo, options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            DefaultBackup::run_backup(token_info, options, progress)
         }
     }
 }
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::Write;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // pretty_env_logger
+    Text,
+    // one json object per line
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(failure::format_err!("unknown log format {}, expected text or json", other)),
+        }
+    }
+}
+
+// Target of the lines written by `middleware::Logger`
+const HTTP_LOG_TARGET: &str = "actix_web::middleware::logger";
+
+// Fields added to every json line logged by the current thread
+#[derive(Debug, Default, Clone, PartialEq)]
+struct LogContext {
+    backup_id: Option<Uuid>,
+    user_id: Option<String>,
+    request_id: Option<String>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+// Every line logged by the current thread carries the backup, the user and the http request
+// that created it, until the scope is dropped. Includes the collectors and the spotify client
+pub struct BackupScope {
+    previous: LogContext,
+}
+
+impl BackupScope {
+    pub fn enter(backup_id: Uuid, user_id: Option<String>, request_id: Option<String>) -> BackupScope {
+        let context = LogContext { backup_id: Some(backup_id), user_id, request_id };
+        let previous = CONTEXT.with(|c| c.replace(context));
+        BackupScope { previous }
+    }
+}
+
+impl Drop for BackupScope {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        CONTEXT.with(|c| c.replace(previous));
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    ts: String,
+    level: &'a str,
+    target: &'a str,
+    thread: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+// Http log lines start with `request_id=<id>`, see `server::HTTP_LOG_FORMAT`. Actix serves many
+// requests from one thread so the id can't be kept in `CONTEXT`
+fn split_request_id(message: String) -> (Option<String>, String) {
+    if !message.starts_with("request_id=") {
+        return (None, message);
+    }
+
+    let mut parts = message["request_id=".len()..].splitn(2, ' ');
+    let id = parts.next().filter(|id| !id.is_empty() && *id != "-").map(|id| id.to_owned());
+    let rest = parts.next().unwrap_or("").to_owned();
+    (id, rest)
+}
+
+fn json_line(record: &log::Record) -> Result<String, serde_json::Error> {
+    let context = CONTEXT.with(|c| c.borrow().clone());
+    let current = std::thread::current();
+
+    let (request_id, message) = if record.target() == HTTP_LOG_TARGET {
+        split_request_id(format!("{}", record.args()))
+    } else {
+        (context.request_id, format!("{}", record.args()))
+    };
+
+    let line = JsonLine {
+        ts: time::now_utc().rfc3339().to_string(),
+        level: record.level().as_str(),
+        target: record.target(),
+        thread: current.name().map(|n| n.to_owned()).unwrap_or_else(|| format!("{:?}", current.id())),
+        message,
+        backup_id: context.backup_id,
+        user_id: context.user_id,
+        request_id,
+    };
+
+    serde_json::to_string(&line)
+}
+
+// Filtered with `RUST_LOG` in both formats
+pub fn init(format: LogFormat) {
+    let mut builder = pretty_env_logger::formatted_builder();
+
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json_line(record).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_scope_sets_context() {
+        let id = Uuid::new_v4();
+
+        {
+            let _scope = BackupScope::enter(id, Some("myuser".into()), Some("req-1".into()));
+            let record = log::Record::builder().args(format_args!("fetching albums")).target("spotify_backup::albums").build();
+            let line: serde_json::Value = serde_json::from_str(&json_line(&record).unwrap()).unwrap();
+
+            assert_eq!(line["message"], "fetching albums");
+            assert_eq!(line["backup_id"], id.to_string());
+            assert_eq!(line["user_id"], "myuser");
+            assert_eq!(line["request_id"], "req-1");
+        }
+
+        assert_eq!(CONTEXT.with(|c| c.borrow().clone()), LogContext::default());
+    }
+
+    #[test]
+    fn test_splits_request_id_from_http_lines() {
+        assert_eq!(split_request_id("request_id=abc 127.0.0.1 \"GET / HTTP/1.1\" 200".into()),
+                   (Some("abc".to_owned()), "127.0.0.1 \"GET / HTTP/1.1\" 200".to_owned()));
+        assert_eq!(split_request_id("request_id=- 127.0.0.1".into()), (None, "127.0.0.1".to_owned()));
+        assert_eq!(split_request_id("Starting worker".into()), (None, "Starting worker".to_owned()));
+    }
+}
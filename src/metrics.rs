@@ -0,0 +1,101 @@
+use failure::Error;
+use lazy_static::lazy_static;
+use prometheus::{Encoder, Histogram, IntCounter, IntCounterVec, IntGaugeVec, TextEncoder};
+use prometheus::{register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge_vec};
+use rspotify::spotify::client::ApiError;
+
+// Served at `/metrics` by the server and by the daemon metrics listener, each process only
+// exposes what happened in it, eg. backups are only counted by the daemon
+lazy_static! {
+    pub static ref BACKUPS_STARTED: IntCounter = register_int_counter!(
+        "spotify_backup_backups_started_total", "Backups claimed by a worker").unwrap();
+
+    pub static ref BACKUPS_COMPLETED: IntCounter = register_int_counter!(
+        "spotify_backup_backups_completed_total", "Backups saved and ready to download").unwrap();
+
+    pub static ref BACKUPS_FAILED: IntCounterVec = register_int_counter_vec!(
+        "spotify_backup_backups_failed_total", "Failed backup attempts, including the ones retried later", &["reason"]).unwrap();
+
+    // cancelled by the user or released because the daemon stopped
+    pub static ref BACKUPS_STOPPED: IntCounterVec = register_int_counter_vec!(
+        "spotify_backup_backups_stopped_total", "Backups stopped before they finished", &["reason"]).unwrap();
+
+    pub static ref BACKUP_DURATION: Histogram = register_histogram!(
+        "spotify_backup_backup_duration_seconds", "Time taken by completed backups",
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0]).unwrap();
+
+    pub static ref BACKUP_FILE_SIZE: Histogram = register_histogram!(
+        "spotify_backup_backup_file_size_bytes", "Size of the backup files written",
+        prometheus::exponential_buckets(1024.0, 4.0, 10).unwrap()).unwrap();
+
+    pub static ref ITEMS_FETCHED: IntCounterVec = register_int_counter_vec!(
+        "spotify_backup_items_fetched_total", "Items fetched from spotify by collection", &["collection"]).unwrap();
+
+    pub static ref SPOTIFY_API_CALLS: IntCounterVec = register_int_counter_vec!(
+        "spotify_backup_spotify_api_calls_total", "Calls to the spotify api by endpoint and response status", &["endpoint", "status"]).unwrap();
+
+    pub static ref RATE_LIMIT_WAITS: Histogram = register_histogram!(
+        "spotify_backup_rate_limit_wait_seconds", "Time waited before retrying a rate limited spotify api call",
+        vec![1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0]).unwrap();
+
+    pub static ref QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "spotify_backup_requests", "Backup requests in the database by status, updated on every scrape", &["status"]).unwrap();
+}
+
+// `endpoint` is the `SpotifyClient` method name, the status is "ok" or the http status spotify answered with
+pub fn observe_api_call<T>(endpoint: &str, result: &Result<T, Error>) {
+    let status = match result {
+        Ok(_) => "ok".to_owned(),
+        Err(err) => match err.downcast_ref::<ApiError>() {
+            Some(ApiError::RateLimited(_)) => "429".to_owned(),
+            Some(ApiError::Unauthorized) => "401".to_owned(),
+            Some(ApiError::Other(status)) => status.to_string(),
+            None => "error".to_owned(),
+        },
+    };
+
+    SPOTIFY_API_CALLS.with_label_values(&[endpoint, &status]).inc();
+}
+
+pub fn set_queue_depth(counts: &[(String, u32)]) {
+    // statuses without requests are not returned by the db, they would otherwise keep their last value
+    QUEUE_DEPTH.reset();
+
+    for (status, count) in counts {
+        QUEUE_DEPTH.with_label_values(&[status]).set(*count as i64);
+    }
+}
+
+// Prometheus text format
+pub fn render() -> Result<String, Error> {
+    let mut buf = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_api_calls_by_status() -> Result<(), Error> {
+        observe_api_call("test_endpoint", &Ok(()));
+        observe_api_call::<()>("test_endpoint", &Err(ApiError::Other(503).into()));
+        observe_api_call::<()>("test_endpoint", &Err(ApiError::Other(503).into()));
+
+        assert_eq!(SPOTIFY_API_CALLS.with_label_values(&["test_endpoint", "ok"]).get(), 1);
+        assert_eq!(SPOTIFY_API_CALLS.with_label_values(&["test_endpoint", "503"]).get(), 2);
+
+        assert!(render()?.contains("spotify_backup_spotify_api_calls_total{endpoint=\"test_endpoint\",status=\"503\"} 2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resets_queue_depth() {
+        set_queue_depth(&[("Pending".to_owned(), 3), ("Running".to_owned(), 1)]);
+        set_queue_depth(&[("Pending".to_owned(), 2)]);
+
+        assert_eq!(QUEUE_DEPTH.with_label_values(&["Pending"]).get(), 2);
+        assert_eq!(QUEUE_DEPTH.with_label_values(&["Running"]).get(), 0);
+    }
+}
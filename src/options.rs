@@ -0,0 +1,252 @@
+use serde::{Serialize, Deserialize};
+use std::str::FromStr;
+use failure::Error;
+use crate::serialize::PlaylistSummary;
+
+// Playlists owned by this user are editorial playlists like "Discover Weekly"
+const SPOTIFY_USER_ID: &str = "spotify";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Collection {
+    Albums,
+    Playlists,
+    LikedSongs,
+    FollowedArtists,
+}
+
+impl Collection {
+    pub fn all() -> Vec<Collection> {
+        vec![Collection::Albums, Collection::Playlists, Collection::LikedSongs, Collection::FollowedArtists]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collection::Albums => "albums",
+            Collection::Playlists => "playlists",
+            Collection::LikedSongs => "liked_songs",
+            Collection::FollowedArtists => "followed_artists",
+        }
+    }
+
+    // Minimal OAuth scopes spotify requires to read this collection
+    pub fn scopes(&self) -> &'static [&'static str] {
+        match self {
+            Collection::Albums => &["user-library-read"],
+            Collection::Playlists => &["playlist-read-private", "playlist-read-collaborative"],
+            Collection::LikedSongs => &["user-library-read"],
+            Collection::FollowedArtists => &["user-follow-read"],
+        }
+    }
+}
+
+impl FromStr for Collection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Collection::all()
+            .into_iter()
+            .find(|c| c.name() == s.trim())
+            .ok_or_else(|| failure::format_err!("unknown collection: {}", s))
+    }
+}
+
+// Which of the user playlists to backup, checked before fetching the playlist tracks.
+// `include` and `exclude` are case insensitive name patterns where `*` matches any text
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct PlaylistFilter {
+    pub owned_only: bool,
+    pub collaborative_only: bool,
+    pub skip_spotify_owned: bool,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl PlaylistFilter {
+    pub fn matches(&self, user_id: &str, playlist: &PlaylistSummary) -> bool {
+        let name = playlist.name.to_lowercase();
+
+        if self.owned_only && playlist.owner_id != user_id {
+            false
+        } else if self.collaborative_only && !playlist.collaborative {
+            false
+        } else if self.skip_spotify_owned && playlist.owner_id == SPOTIFY_USER_ID {
+            false
+        } else if !self.include.is_empty() && !self.include.iter().any(|p| name_matches(p, &name)) {
+            false
+        } else {
+            !self.exclude.iter().any(|p| name_matches(p, &name))
+        }
+    }
+}
+
+fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let mut parts = pattern.split('*');
+
+    // No `*` in the pattern, match the whole name
+    let first = parts.next().unwrap_or("");
+    if !pattern.contains('*') {
+        return name == first;
+    }
+
+    if !name.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+
+    for (idx, part) in parts.iter().enumerate() {
+        let is_last = idx == parts.len() - 1;
+
+        if is_last {
+            return rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
+// What a single backup should include, saved with the backup request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupOptions {
+    pub collections: Vec<Collection>,
+    #[serde(default)]
+    pub playlist_filter: PlaylistFilter,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            collections: vec![Collection::Albums, Collection::Playlists],
+            playlist_filter: PlaylistFilter::default(),
+        }
+    }
+}
+
+impl BackupOptions {
+    pub fn new(mut collections: Vec<Collection>) -> BackupOptions {
+        collections.sort();
+        collections.dedup();
+
+        if collections.is_empty() {
+            BackupOptions::default()
+        } else {
+            BackupOptions { collections, playlist_filter: PlaylistFilter::default() }
+        }
+    }
+
+    // Parses a comma separated list of collection names, eg. `albums,liked_songs`
+    pub fn parse_collections(s: &str) -> Result<BackupOptions, Error> {
+        let collections = s
+            .split(',')
+            .filter(|c| !c.trim().is_empty())
+            .map(Collection::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(BackupOptions::new(collections))
+    }
+
+    pub fn includes(&self, collection: Collection) -> bool {
+        self.collections.contains(&collection)
+    }
+
+    pub fn scope(&self) -> String {
+        let mut scopes: Vec<&str> = self.collections.iter().flat_map(|c| c.scopes().iter().cloned()).collect();
+        scopes.sort();
+        scopes.dedup();
+        scopes.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_scope() {
+        assert_eq!(BackupOptions::default().scope(), "playlist-read-collaborative playlist-read-private user-library-read");
+    }
+
+    #[test]
+    fn test_scope_only_includes_selected_collections() {
+        let options = BackupOptions::new(vec![Collection::LikedSongs, Collection::Albums]);
+        assert_eq!(options.scope(), "user-library-read");
+
+        let options = BackupOptions::new(vec![Collection::FollowedArtists]);
+        assert_eq!(options.scope(), "user-follow-read");
+    }
+
+    #[test]
+    fn test_parse_collections() -> Result<(), Error> {
+        let options = BackupOptions::parse_collections("liked_songs, albums,liked_songs")?;
+        assert_eq!(options.collections, vec![Collection::Albums, Collection::LikedSongs]);
+
+        assert!(BackupOptions::parse_collections("albums,podcasts").is_err());
+        assert_eq!(BackupOptions::parse_collections("")?, BackupOptions::default());
+        Ok(())
+    }
+
+    fn playlist(name: &str, owner_id: &str, collaborative: bool) -> PlaylistSummary {
+        PlaylistSummary {
+            id: format!("id-{}", name),
+            name: name.into(),
+            owner_id: owner_id.into(),
+            collaborative,
+        }
+    }
+
+    #[test]
+    fn test_default_filter_matches_everything() {
+        let filter = PlaylistFilter::default();
+
+        assert!(filter.matches("myuser", &playlist("Mine", "myuser", false)));
+        assert!(filter.matches("myuser", &playlist("Discover Weekly", "spotify", false)));
+    }
+
+    #[test]
+    fn test_filter_by_owner() {
+        let filter = PlaylistFilter { owned_only: true, ..PlaylistFilter::default() };
+        assert!(filter.matches("myuser", &playlist("Mine", "myuser", false)));
+        assert!(!filter.matches("myuser", &playlist("Followed", "otheruser", false)));
+
+        let filter = PlaylistFilter { skip_spotify_owned: true, ..PlaylistFilter::default() };
+        assert!(filter.matches("myuser", &playlist("Followed", "otheruser", false)));
+        assert!(!filter.matches("myuser", &playlist("Discover Weekly", "spotify", false)));
+
+        let filter = PlaylistFilter { collaborative_only: true, ..PlaylistFilter::default() };
+        assert!(filter.matches("myuser", &playlist("Shared", "otheruser", true)));
+        assert!(!filter.matches("myuser", &playlist("Mine", "myuser", false)));
+    }
+
+    #[test]
+    fn test_filter_by_name() {
+        let filter = PlaylistFilter {
+            include: vec!["road trip*".into(), "*mix".into()],
+            exclude: vec!["*old*".into()],
+            ..PlaylistFilter::default()
+        };
+
+        assert!(filter.matches("myuser", &playlist("Road Trip 2019", "myuser", false)));
+        assert!(filter.matches("myuser", &playlist("Daily Mix", "spotify", false)));
+        assert!(!filter.matches("myuser", &playlist("Road Trip Old", "myuser", false)));
+        assert!(!filter.matches("myuser", &playlist("Workout", "myuser", false)));
+    }
+
+    #[test]
+    fn test_name_patterns() {
+        assert!(name_matches("workout", "workout"));
+        assert!(!name_matches("workout", "workout 2"));
+        assert!(name_matches("*", "anything"));
+        assert!(name_matches("a*c*e", "abcde"));
+        assert!(!name_matches("a*c*e", "abcdef"));
+        assert!(name_matches(" Jazz* ", "jazz classics"));
+    }
+}
@@ -3,42 +3,81 @@ use failure::Error;
 
 use crate::serialize::*;
 use crate::spotify::client::*;
+use crate::progress::Progress;
+use crate::options::PlaylistFilter;
+
+// Called before each request to spotify, fails to stop the backup, eg. when it was cancelled.
+// These errors are returned as is, unlike failed playlists they are not recorded in `errors`
+type StopCheck<'a> = &'a dyn Fn() -> Result<(), Error>;
+
+fn playlist_error(id: &PlaylistId, name: Option<&str>, err: &Error) -> BackupItemError {
+    log::warn!("Could not backup playlist {} ({:?}): {}", id, name, err);
+
+    BackupItemError {
+        collection: "playlists".into(),
+        id: id.clone(),
+        name: name.map(|n| n.to_owned()),
+        message: format!("{}", err),
+    }
+}
+
+fn fetch_tracks(
+    user_id: &str,
+    spotify: &dyn SpotifyClient,
+    playlist_id: &PlaylistId,
+    first_page: &Page<Track>,
+    check: StopCheck,
+) -> Result<Result<Vec<Track>, Error>, Error> {
+    let mut tracks = vec![];
+    let mut next_page: Page<Track> = first_page.clone();
+
+    loop {
+        tracks.append(&mut next_page.items);
+
+        let offset = next_page.offset + next_page.limit;
+
+        if next_page.next.is_some() {
+            check()?;
+
+            next_page = match spotify.playlist_tracks(user_id, playlist_id, Some(next_page.limit), Some(offset)) {
+                Ok(page) => page,
+                Err(err) => return Ok(Err(err)),
+            };
+        } else {
+            break;
+        }
+    }
+
+    Ok(Ok(tracks))
+}
 
 fn extract_playlists(
     user_id: &str,
     spotify: &dyn SpotifyClient,
     dest: &mut Vec<Playlist>,
+    errors: &mut Vec<BackupItemError>,
     playlists: Vec<(Playlist, Page<Track>)>,
+    check: StopCheck,
 ) -> Result<(), Error> {
     for (p, page) in &playlists {
         log::debug!("Parsing playlist {:?}", p.name);
 
-        let mut tracks = vec![];
-        let mut next_page: Page<Track> = page.clone();
-
-        loop {
-            tracks.append(&mut next_page.items);
+        match fetch_tracks(user_id, spotify, &p.id, page, check)? {
+            Ok(tracks) => {
+                let track_count = tracks.len();
 
-            let offset = next_page.offset + next_page.limit;
+                let parsed_playlist = Playlist {
+                    id: p.id.clone(),
+                    name: p.name.clone(),
+                    tracks,
+                    track_count,
+                };
 
-            if next_page.next.is_some() {
-                next_page = spotify
-                    .playlist_tracks(user_id, &p.id, Some(next_page.limit), Some(offset))?;
-            } else {
-                break;
+                dest.push(parsed_playlist);
             }
+            Err(err) =>
+                errors.push(playlist_error(&p.id, Some(&p.name), &err))
         }
-
-        let track_count = tracks.len();
-
-        let parsed_playlist = Playlist {
-            id: p.id.clone(),
-            name: p.name.clone(),
-            tracks,
-            track_count,
-        };
-
-        dest.push(parsed_playlist);
     }
 
     Ok(())
@@ -46,22 +85,57 @@ fn extract_playlists(
 
 fn get_full_playlists(
     spotify: &dyn SpotifyClient,
+    errors: &mut Vec<BackupItemError>,
     playlist_ids: Vec<PlaylistId>,
+    check: StopCheck,
 ) -> Result<Vec<(Playlist, Page<Track>)>, Error> {
-    playlist_ids
-        .iter()
-        .map(|p| spotify.playlist(&p))
+    let mut full_playlists = vec![];
+
+    for id in playlist_ids.iter() {
+        check()?;
+
+        match spotify.playlist(id) {
+            Ok(p) => full_playlists.push(p),
+            Err(err) => errors.push(playlist_error(id, None, &err)),
+        }
+    }
+
+    Ok(full_playlists)
+}
+
+fn filter_playlists(user_id: &str, filter: &PlaylistFilter, playlists: Vec<PlaylistSummary>) -> Vec<PlaylistId> {
+    playlists
+        .into_iter()
+        .filter(|p| {
+            let selected = filter.matches(user_id, p);
+
+            if !selected {
+                log::debug!("Skipping playlist {:?}, excluded by filter", p.name);
+            }
+
+            selected
+        })
+        .map(|p| p.id)
         .collect()
 }
 
-pub fn backup_playlists(user_id: &str, spotify: &dyn SpotifyClient) -> Result<Vec<Playlist>, Error> {
+// Playlists that cannot be fetched are recorded in `errors` and skipped, but failing
+// to list the user playlists still fails the whole backup
+pub fn backup_playlists(user_id: &str, spotify: &dyn SpotifyClient, filter: &PlaylistFilter, errors: &mut Vec<BackupItemError>, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Vec<Playlist>, Error> {
     let mut parsed_playlists: Vec<Playlist> = vec![];
     let mut offset = 0;
 
     loop {
         let playlists = spotify.playlists(user_id, Some(50), Some(offset))?;
-        let full_playlists = get_full_playlists(spotify, playlists.items)?;
-        extract_playlists(user_id, spotify, &mut parsed_playlists, full_playlists)?;
+        let fetched = offset + playlists.items.len() as u32;
+        let total = playlists.total;
+        let check = || progress(&Progress::new("playlists", offset, total));
+
+        let playlist_ids = filter_playlists(user_id, filter, playlists.items);
+        let full_playlists = get_full_playlists(spotify, errors, playlist_ids, &check)?;
+        extract_playlists(user_id, spotify, &mut parsed_playlists, errors, full_playlists, &check)?;
+
+        progress(&Progress::new("playlists", fetched, playlists.total))?;
 
         if playlists.next.is_none() {
             break;
@@ -77,39 +151,10 @@ pub fn backup_playlists(user_id: &str, spotify: &dyn SpotifyClient) -> Result<Ve
 mod tests {
     use super::*;
     use mockall::predicate::*;
-    use mockall::*;
     use rspotify::spotify::model::page::Page;
-
-    mock! {
-        pub SpotifyClientM { }
-        trait SpotifyClient {
-            fn saved_albums(
-                &self,
-                limit: Option<u32>,
-                offset: Option<u32>
-            ) -> Result<Page<Album>, Error>;
-
-            fn playlists(
-                &self,
-                user_id: &str,
-                limit: Option<u32>,
-                offset: Option<u32>,
-            ) -> Result<Page<PlaylistId>, Error>;
-
-            fn playlist(
-                &self,
-                playlist_id: &PlaylistId,
-            ) -> Result<(Playlist, Page<Track>), Error>;
-
-            fn playlist_tracks(
-                &self,
-                user_id: &str,
-                playlist_id: &PlaylistId,
-                limit: Option<u32>,
-                offset: Option<u32>,
-            ) -> Result<Page<Track>, Error>;
-        }
-    }
+    use crate::spotify::client::MockSpotifyClient;
+    use crate::progress::Cancelled;
+    use std::cell::Cell;
 
     fn new_page<T>(items: Vec<T>, offset: u32, total: u32, next: Option<String>) -> Page<T> {
         Page {
@@ -123,6 +168,15 @@ mod tests {
         }
     }
 
+    fn summary(id: &str) -> PlaylistSummary {
+        PlaylistSummary {
+            id: id.into(),
+            name: format!("Playlist: {}", id),
+            owner_id: "myuser".into(),
+            collaborative: false,
+        }
+    }
+
     fn new_track(name: &str) -> Track {
         let artist = Artist {
             name: format!("Artist: {}", name),
@@ -143,25 +197,25 @@ mod tests {
 
     #[test]
     fn test_backup_playlists_empty() {
-        let mut mock = MockSpotifyClientM::new();
+        let mut mock = MockSpotifyClient::new();
 
         mock.expect_playlists()
             .with(eq("myuser"), eq(Some(50)), eq(Some(0)))
             .times(1)
             .returning(|_, _, _| Ok(new_page(vec![], 0, 0, None)));
 
-        let backup = backup_playlists("myuser", &mock).unwrap();
+        let backup = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut vec![], &|_| Ok(())).unwrap();
         assert_eq!(backup.len(), 0)
     }
 
     #[test]
     fn test_backup_playlists_single_playlist() {
-        let mut mock = MockSpotifyClientM::new();
+        let mut mock = MockSpotifyClient::new();
 
         mock.expect_playlists()
             .with(eq("myuser"), eq(Some(50)), eq(Some(0)))
             .times(1)
-            .returning(|_, _, _| Ok(new_page(vec!["playlist-id-01".into()], 0, 1, None)));
+            .returning(|_, _, _| Ok(new_page(vec![summary("playlist-id-01")], 0, 1, None)));
 
         mock.expect_playlist_tracks()
             .with(
@@ -194,7 +248,7 @@ mod tests {
                 Ok((playlist, tracks_page))
             });
 
-        let backup = backup_playlists("myuser", &mock).unwrap();
+        let backup = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut vec![], &|_| Ok(())).unwrap();
         assert_eq!(backup.len(), 1);
 
         let playlist = backup.get(0).unwrap();
@@ -214,14 +268,14 @@ mod tests {
 
     #[test]
     fn test_backup_playlists_multiple_pages() {
-        let mut mock = MockSpotifyClientM::new();
+        let mut mock = MockSpotifyClient::new();
 
         mock.expect_playlists()
             .with(eq("myuser"), eq(Some(50)), eq(Some(0)))
             .times(1)
             .returning(|_, _, _| {
                 Ok(new_page(
-                    vec!["playlist-id-01".into()],
+                    vec![summary("playlist-id-01")],
                     0,
                     1,
                     Some("https://second-playlist-page".into()),
@@ -231,7 +285,7 @@ mod tests {
         mock.expect_playlists()
             .with(eq("myuser"), eq(Some(50)), eq(Some(50)))
             .times(1)
-            .returning(|_, _, _| Ok(new_page(vec!["playlist-id-02".into()], 0, 1, None)));
+            .returning(|_, _, _| Ok(new_page(vec![summary("playlist-id-02")], 0, 1, None)));
 
         mock.expect_playlist().times(2).returning(|id| {
             let playlist = Playlist {
@@ -247,7 +301,7 @@ mod tests {
             Ok((playlist, tracks_page))
         });
 
-        let backup = backup_playlists("myuser", &mock).unwrap();
+        let backup = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut vec![], &|_| Ok(())).unwrap();
         assert_eq!(backup.len(), 2);
 
         let mut playlist = backup.get(0).unwrap();
@@ -262,4 +316,177 @@ mod tests {
         assert_eq!(playlist.tracks.len(), 1);
         assert_eq!(playlist.track_count, 1);
     }
+
+    #[test]
+    fn test_backup_playlists_skips_failed_playlists() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_playlists()
+            .with(eq("myuser"), eq(Some(50)), eq(Some(0)))
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(new_page(vec![summary("playlist-id-01"), summary("playlist-id-02")], 0, 2, None))
+            });
+
+        mock.expect_playlist()
+            .with(eq("playlist-id-01".to_owned()))
+            .times(1)
+            .returning(|_| failure::bail!("[test] playlist not found"));
+
+        mock.expect_playlist()
+            .with(eq("playlist-id-02".to_owned()))
+            .times(1)
+            .returning(|id| {
+                let playlist = Playlist {
+                    id: id.into(),
+                    name: "Playlist 02".into(),
+                    tracks: vec![],
+                    track_count: 0,
+                };
+
+                Ok((playlist, new_page(vec![new_track("My Track")], 0, 1, None)))
+            });
+
+        let mut errors = vec![];
+        let backup = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut errors, &|_| Ok(())).unwrap();
+
+        assert_eq!(backup.len(), 1);
+        assert_eq!(backup.get(0).unwrap().id, "playlist-id-02");
+
+        assert_eq!(errors.len(), 1);
+        let error = errors.get(0).unwrap();
+        assert_eq!(error.collection, "playlists");
+        assert_eq!(error.id, "playlist-id-01");
+        assert_eq!(error.name, None);
+        assert_eq!(error.message, "[test] playlist not found");
+    }
+
+    #[test]
+    fn test_backup_playlists_skips_playlist_if_tracks_fail() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_playlists()
+            .times(1)
+            .returning(|_, _, _| Ok(new_page(vec![summary("playlist-id-01")], 0, 1, None)));
+
+        mock.expect_playlist()
+            .times(1)
+            .returning(|id| {
+                let playlist = Playlist {
+                    id: id.into(),
+                    name: "Playlist 01".into(),
+                    tracks: vec![],
+                    track_count: 0,
+                };
+
+                let tracks_page =
+                    new_page(vec![new_track("My Track")], 0, 2, Some("http://some-other-page".to_owned()));
+
+                Ok((playlist, tracks_page))
+            });
+
+        mock.expect_playlist_tracks()
+            .times(1)
+            .returning(|_, _, _, _| failure::bail!("[test] forbidden"));
+
+        let mut errors = vec![];
+        let backup = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut errors, &|_| Ok(())).unwrap();
+
+        assert!(backup.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors.get(0).unwrap().name, Some("Playlist 01".to_owned()));
+    }
+
+    #[test]
+    fn test_backup_playlists_does_not_fetch_filtered_playlists() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_playlists()
+            .times(1)
+            .returning(|_, _, _| {
+                let mut followed = summary("playlist-id-02");
+                followed.owner_id = "spotify".into();
+
+                Ok(new_page(vec![summary("playlist-id-01"), followed], 0, 2, None))
+            });
+
+        mock.expect_playlist()
+            .with(eq("playlist-id-01".to_owned()))
+            .times(1)
+            .returning(|id| {
+                let playlist = Playlist {
+                    id: id.into(),
+                    name: "Playlist 01".into(),
+                    tracks: vec![],
+                    track_count: 0,
+                };
+
+                Ok((playlist, new_page(vec![new_track("My Track")], 0, 1, None)))
+            });
+
+        let filter = PlaylistFilter { owned_only: true, ..PlaylistFilter::default() };
+        let backup = backup_playlists("myuser", &mock, &filter, &mut vec![], &|_| Ok(())).unwrap();
+
+        assert_eq!(backup.len(), 1);
+        assert_eq!(backup.get(0).unwrap().id, "playlist-id-01");
+    }
+
+    #[test]
+    fn test_backup_playlists_stops_between_playlists() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_playlists()
+            .times(1)
+            .returning(|_, _, _| Ok(new_page(vec![summary("playlist-id-01")], 0, 1, None)));
+
+        mock.expect_playlist().times(0);
+
+        let mut errors = vec![];
+        let res = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut errors, &|_| Err(Cancelled.into()));
+
+        assert!(res.unwrap_err().downcast_ref::<Cancelled>().is_some());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_backup_playlists_stops_between_track_pages() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_playlists()
+            .times(1)
+            .returning(|_, _, _| Ok(new_page(vec![summary("playlist-id-01")], 0, 1, None)));
+
+        mock.expect_playlist()
+            .times(1)
+            .returning(|id| {
+                let playlist = Playlist {
+                    id: id.into(),
+                    name: "Playlist 01".into(),
+                    tracks: vec![],
+                    track_count: 0,
+                };
+
+                Ok((playlist, new_page(vec![new_track("My Track")], 0, 2, Some("http://some-other-page".to_owned()))))
+            });
+
+        mock.expect_playlist_tracks().times(0);
+
+        // cancelled after the playlist was fetched, before its second page of tracks
+        let calls = Cell::new(0);
+        let progress = |_: &Progress| -> Result<(), Error> {
+            calls.set(calls.get() + 1);
+
+            if calls.get() > 1 {
+                Err(Cancelled.into())
+            } else {
+                Ok(())
+            }
+        };
+
+        let mut errors = vec![];
+        let res = backup_playlists("myuser", &mock, &PlaylistFilter::default(), &mut errors, &progress);
+
+        assert!(res.unwrap_err().downcast_ref::<Cancelled>().is_some());
+        assert!(errors.is_empty());
+    }
 }
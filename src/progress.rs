@@ -0,0 +1,31 @@
+use serde::{Serialize, Deserialize};
+
+// How far a backup is in fetching one of its collections, `total` comes from the spotify `Page`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub collection: String,
+    pub fetched: u32,
+    pub total: u32,
+}
+
+impl Progress {
+    pub fn new(collection: &str, fetched: u32, total: u32) -> Progress {
+        Progress {
+            collection: collection.into(),
+            fetched,
+            total,
+        }
+    }
+}
+
+// Returned by a progress callback to stop a backup between pages, eg. when the user cancelled it
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "backup was cancelled")
+    }
+}
+
+impl failure::Fail for Cancelled {}
@@ -1,11 +1,25 @@
 use rspotify::spotify::senum::AlbumType;
 use serde::Serialize;
+use crate::options::Collection;
 
 pub type PlaylistId = String;
 #[derive(Serialize, Debug, Clone)]
 pub struct Backup {
+    pub collections: Vec<Collection>,
     pub albums: Vec<Album>,
     pub playlists: Vec<Playlist>,
+    pub liked_songs: Vec<Track>,
+    pub followed_artists: Vec<Artist>,
+    pub errors: Vec<BackupItemError>,
+}
+
+// An item that could not be backed up, the rest of the backup still completed
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BackupItemError {
+    pub collection: String,
+    pub id: String,
+    pub name: Option<String>,
+    pub message: String,
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq)]
@@ -27,6 +41,15 @@ pub struct Track {
     pub album: Album,
 }
 
+// What spotify returns when listing playlists, enough to filter them before fetching all tracks
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistSummary {
+    pub id: PlaylistId,
+    pub name: String,
+    pub owner_id: String,
+    pub collaborative: bool,
+}
+
 #[derive(Serialize, Debug, Clone)]
 pub struct Playlist {
     #[serde(skip)]
@@ -0,0 +1,237 @@
+use actix_web::{HttpResponse, web};
+use actix_session::Session;
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use uuid::Uuid;
+use std::sync::Arc;
+
+use super::app::{self, DefaultRenderer};
+use super::db::{self, AdminBackupRequest, Pool, RequestStatus};
+use super::store::BackupStore;
+use super::crypto::Keyring;
+
+// How many requests are listed at once, newest first
+const PAGE_SIZE: u32 = 100;
+
+// Spotify user ids from `admin_users`, everybody else gets a 404 from every admin page
+#[derive(Clone)]
+pub struct AdminUsers(Vec<String>);
+
+impl AdminUsers {
+    pub fn new(users: Vec<String>) -> AdminUsers {
+        AdminUsers(users)
+    }
+
+    fn check(&self, session: &Session) -> Result<String, actix_web::error::Error> {
+        match app::session_user(session) {
+            Some(user) if self.0.contains(&user) => Ok(user),
+            _ => Err(actix_web::error::ErrorNotFound("not found")),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    // failed requests only
+    Retry,
+    // pending or running requests only
+    Cancel,
+    // anything but running requests, also deletes the backup file
+    Purge,
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    status: Option<RequestStatus>,
+}
+
+#[derive(Serialize)]
+pub struct AdminEntry {
+    pub backup_id: Uuid,
+    pub status: RequestStatus,
+    pub user_id: Option<String>,
+    pub schedule_id: Option<Uuid>,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    // how long the last attempt ran, or has been running
+    pub duration: Option<String>,
+    pub attempts: u32,
+    pub claimed_by: Option<String>,
+    pub error: Option<String>,
+    pub warnings: u32,
+    pub size: Option<String>,
+}
+
+impl AdminEntry {
+    fn new(req: AdminBackupRequest, now: time::Timespec) -> AdminEntry {
+        let duration = req.started_at.map(|started| {
+            let finished = match req.status {
+                RequestStatus::Running => now,
+                _ => req.finished_at.unwrap_or(now),
+            };
+
+            format_duration(finished - started)
+        });
+
+        AdminEntry {
+            backup_id: req.id,
+            status: req.status,
+            user_id: req.user_id,
+            schedule_id: req.schedule_id,
+            created_at: app::format_time(req.time_created),
+            started_at: req.started_at.map(app::format_time),
+            duration,
+            attempts: req.attempts,
+            claimed_by: req.claimed_by,
+            error: req.last_error,
+            warnings: req.warning_count,
+            size: req.file_size.map(app::format_size),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatusCount {
+    pub status: RequestStatus,
+    pub count: u32,
+}
+
+#[derive(Serialize)]
+pub struct AdminResponse {
+    pub status: Option<RequestStatus>,
+    // pending requests, including the ones waiting for a retry
+    pub queued: u32,
+    pub counts: Vec<StatusCount>,
+    pub backups: Vec<AdminEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ActionResponse {
+    pub backup_id: Uuid,
+    pub action: Action,
+    // false if the request does not exist or the action does not apply to its status
+    pub done: bool,
+}
+
+fn format_duration(d: time::Duration) -> String {
+    let secs = d.num_seconds().max(0);
+
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, _) => format!("{}h {}m", h, m),
+    }
+}
+
+async fn load(pool: &Pool, status: Option<RequestStatus>) -> Result<AdminResponse, actix_web::error::Error> {
+    let p = pool.clone();
+    let filter = status.clone();
+
+    let (counts, requests) = web::block(move || -> Result<_, failure::Error> {
+        let counts = db::admin::count_by_status(p.get()?)?;
+        let requests = db::admin::find_requests(p.get()?, filter, PAGE_SIZE)?;
+        Ok((counts, requests))
+    }).await?;
+
+    let now = time::get_time();
+
+    let queued = counts.iter()
+        .filter(|(s, _)| *s == RequestStatus::Pending)
+        .map(|(_, count)| *count)
+        .sum();
+
+    Ok(AdminResponse {
+        status,
+        queued,
+        counts: counts.into_iter().map(|(status, count)| StatusCount { status, count }).collect(),
+        backups: requests.into_iter().map(|req| AdminEntry::new(req, now)).collect(),
+    })
+}
+
+fn apply(pool: &Pool, keyring: Option<&Keyring>, store: &dyn BackupStore, id: Uuid, action: Action) -> Result<bool, failure::Error> {
+    match action {
+        Action::Retry => db::admin::requeue(pool.get()?, id),
+        Action::Cancel => db::backup_request::cancel(pool.get()?, id),
+        Action::Purge => {
+            let file = db::backup_request::find_with_status(pool.get()?, keyring, id)?.and_then(|(req, _)| req.file);
+
+            if !db::admin::purge(pool.get()?, id)? {
+                return Ok(false);
+            }
+
+            if let Some(file) = file {
+                store.delete(&file.to_string_lossy())?;
+            }
+
+            Ok(true)
+        }
+    }
+}
+
+async fn run(admin: String, pool: &Pool, keyring: &Option<Arc<Keyring>>, store: &Arc<dyn BackupStore>, id: Uuid, action: Action) -> Result<ActionResponse, actix_web::error::Error> {
+    let p = pool.clone();
+    let k = keyring.clone();
+    let s = store.clone();
+
+    let done = web::block(move || apply(&p, k.as_deref(), s.as_ref(), id, action)).await?;
+
+    if done {
+        log::info!("Admin {} applied {:?} to backup {}", admin, action, id);
+    } else {
+        log::info!("Admin {} could not apply {:?} to backup {}", admin, action, id);
+    }
+
+    Ok(ActionResponse { backup_id: id, action, done })
+}
+
+pub async fn index(params: web::Query<ListParams>, session: Session, admins: web::Data<AdminUsers>, pool: web::Data<Pool>,
+                   renderer: web::Data<DefaultRenderer>) -> Result<HttpResponse, actix_web::error::Error> {
+    admins.check(&session)?;
+
+    let resp = load(&pool, params.into_inner().status).await?;
+
+    let mut ctx = Context::from_serialize(resp).map_err(actix_web::error::ErrorInternalServerError)?;
+    let body = renderer.render("admin.html", &mut ctx).map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body(body))
+}
+
+// Form posts from the admin page, redirects back to it
+pub async fn backup_action(path: web::Path<(Uuid, Action)>, session: Session, admins: web::Data<AdminUsers>, pool: web::Data<Pool>,
+                           store: web::Data<Arc<dyn BackupStore>>, keyring: web::Data<Option<Arc<Keyring>>>, renderer: web::Data<DefaultRenderer>) -> Result<HttpResponse, actix_web::error::Error> {
+    let admin = admins.check(&session)?;
+    let (id, action) = path.into_inner();
+
+    run(admin, &pool, keyring.get_ref(), &store, id, action).await?;
+
+    Ok(app::redirect(format!("{}/admin", renderer.base_path)))
+}
+
+pub async fn api_backups(params: web::Query<ListParams>, session: Session, admins: web::Data<AdminUsers>, pool: web::Data<Pool>) -> Result<HttpResponse, actix_web::error::Error> {
+    admins.check(&session)?;
+
+    let resp = load(&pool, params.into_inner().status).await?;
+    Ok(HttpResponse::Ok().json(resp))
+}
+
+pub async fn api_backup_action(path: web::Path<(Uuid, Action)>, session: Session, admins: web::Data<AdminUsers>, pool: web::Data<Pool>,
+                               store: web::Data<Arc<dyn BackupStore>>, keyring: web::Data<Option<Arc<Keyring>>>) -> Result<HttpResponse, actix_web::error::Error> {
+    let admin = admins.check(&session)?;
+    let (id, action) = path.into_inner();
+
+    let resp = run(admin, &pool, keyring.get_ref(), &store, id, action).await?;
+    Ok(HttpResponse::Ok().json(resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_durations() {
+        assert_eq!(format_duration(time::Duration::seconds(42)), "42s");
+        assert_eq!(format_duration(time::Duration::seconds(125)), "2m 5s");
+        assert_eq!(format_duration(time::Duration::minutes(150)), "2h 30m");
+        assert_eq!(format_duration(time::Duration::seconds(-3)), "0s");
+    }
+}
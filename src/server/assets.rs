@@ -0,0 +1,137 @@
+use actix_web::{HttpRequest, HttpResponse, web};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tera::Tera;
+
+// Compiled into the binary so it runs from any directory. Files in `templates_dir` and
+// `static_dir` are used instead of these when they have the same name
+const TEMPLATES: &[(&str, &str)] = &[
+    ("base.html", include_str!("../../templates/base.html")),
+    ("index.html", include_str!("../../templates/index.html")),
+    ("backup.html", include_str!("../../templates/backup.html")),
+    ("history.html", include_str!("../../templates/history.html")),
+    ("schedule.html", include_str!("../../templates/schedule.html")),
+    ("admin.html", include_str!("../../templates/admin.html")),
+    ("error.html", include_str!("../../templates/error.html")),
+];
+
+const STATIC_FILES: &[(&str, &[u8])] = &[
+    ("miligram.css", include_bytes!("../../static/miligram.css")),
+    ("roboto.css", include_bytes!("../../static/roboto.css")),
+    ("fonts/roboto-v20-latin-300.woff", include_bytes!("../../static/fonts/roboto-v20-latin-300.woff")),
+    ("fonts/roboto-v20-latin-300.woff2", include_bytes!("../../static/fonts/roboto-v20-latin-300.woff2")),
+    ("fonts/roboto-v20-latin-300italic.woff", include_bytes!("../../static/fonts/roboto-v20-latin-300italic.woff")),
+    ("fonts/roboto-v20-latin-300italic.woff2", include_bytes!("../../static/fonts/roboto-v20-latin-300italic.woff2")),
+    ("fonts/roboto-v20-latin-700.woff", include_bytes!("../../static/fonts/roboto-v20-latin-700.woff")),
+    ("fonts/roboto-v20-latin-700.woff2", include_bytes!("../../static/fonts/roboto-v20-latin-700.woff2")),
+    ("fonts/roboto-v20-latin-700italic.woff", include_bytes!("../../static/fonts/roboto-v20-latin-700italic.woff")),
+    ("fonts/roboto-v20-latin-700italic.woff2", include_bytes!("../../static/fonts/roboto-v20-latin-700italic.woff2")),
+];
+
+// Embedded templates, replaced or extended by the `.html` files in `overrides`
+pub fn load_templates(overrides: Option<&Path>) -> Result<Tera, failure::Error> {
+    let mut templates: HashMap<String, String> = TEMPLATES.iter()
+        .map(|(name, content)| (name.to_string(), content.to_string()))
+        .collect();
+
+    if let Some(dir) = overrides {
+        for entry in std::fs::read_dir(dir).map_err(|err| failure::format_err!("Could not read templates_dir {:?}: {}", dir, err))? {
+            let path = entry?.path();
+
+            if path.extension().map(|e| e == "html").unwrap_or(false) {
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                log::info!("Using template {} from {:?}", name, dir);
+                templates.insert(name, std::fs::read_to_string(&path)?);
+            }
+        }
+    }
+
+    let mut tera = Tera::default();
+    tera.add_raw_templates(templates.iter().map(|(name, content)| (name.as_str(), content.as_str())).collect::<Vec<_>>())?;
+    Ok(tera)
+}
+
+// Where static files are looked up before the embedded ones
+#[derive(Clone)]
+pub struct StaticOverrides(pub Option<PathBuf>);
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next() {
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+// `None` for paths trying to leave the directory
+fn override_path(dir: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path);
+
+    if relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(dir.join(relative))
+    } else {
+        None
+    }
+}
+
+pub async fn static_file(req: HttpRequest, overrides: web::Data<StaticOverrides>) -> Result<HttpResponse, actix_web::error::Error> {
+    let path = req.match_info().query("path").to_owned();
+
+    let from_dir = match overrides.0.as_ref().and_then(|dir| override_path(dir, &path)) {
+        Some(file) => web::block(move || -> Result<Option<Vec<u8>>, std::io::Error> {
+            match std::fs::read(&file) {
+                Ok(content) => Ok(Some(content)),
+                Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            }
+        }).await?,
+        None => None,
+    };
+
+    let content = match from_dir {
+        Some(content) => content,
+        None => match STATIC_FILES.iter().find(|(name, _)| *name == path) {
+            Some((_, content)) => content.to_vec(),
+            None => return Ok(HttpResponse::NotFound().finish()),
+        },
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type(&path))
+        .body(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_templates_can_be_overridden() -> Result<(), failure::Error> {
+        let dir = tempdir()?;
+        std::fs::write(dir.path().join("index.html"), r#"{% extends "base.html" %}{% block content %}custom{% endblock content %}"#)?;
+
+        let tera = load_templates(Some(dir.path()))?;
+        let mut ctx = tera::Context::new();
+        ctx.insert("base_path", "");
+
+        assert!(tera.render("index.html", &ctx)?.contains("custom"));
+        assert!(tera.get_template_names().any(|t| t == "admin.html"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_paths_outside_overrides() {
+        let dir = Path::new("/srv/static");
+
+        assert_eq!(override_path(dir, "fonts/a.woff"), Some(PathBuf::from("/srv/static/fonts/a.woff")));
+        assert_eq!(override_path(dir, "../config.toml"), None);
+        assert_eq!(override_path(dir, "/etc/passwd"), None);
+    }
+}
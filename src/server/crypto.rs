@@ -0,0 +1,256 @@
+use failure::Error;
+use ring::hmac;
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::config::Config;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| failure::format_err!("could not generate random bytes"))?;
+    Ok(bytes)
+}
+
+// AES-256-GCM key used to encrypt spotify tokens before saving them to the db
+#[derive(Clone)]
+pub struct TokenKey(Vec<u8>);
+
+impl TokenKey {
+    // `encoded` is a base64 encoded 32 byte key, eg. generated with `openssl rand -base64 32`
+    pub fn from_base64(encoded: &str) -> Result<TokenKey, Error> {
+        let key = base64::decode(encoded.trim())?;
+
+        if key.len() != AES_256_GCM.key_len() {
+            failure::bail!("token key must be {} bytes, got {}", AES_256_GCM.key_len(), key.len())
+        }
+
+        Ok(TokenKey(key))
+    }
+
+    fn aead_key(&self) -> Result<LessSafeKey, Error> {
+        let key = UnboundKey::new(&AES_256_GCM, &self.0).map_err(|_| failure::format_err!("invalid token key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    // Returns `<nonce>.<ciphertext>`, both base64 encoded
+    pub fn seal(&self, plaintext: &str) -> Result<String, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| failure::format_err!("could not generate nonce"))?;
+
+        let mut in_out = plaintext.as_bytes().to_vec();
+
+        self.aead_key()?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+            .map_err(|_| failure::format_err!("could not encrypt token"))?;
+
+        Ok(format!("{}.{}", base64::encode(&nonce), base64::encode(&in_out)))
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<String, Error> {
+        let mut parts = sealed.splitn(2, '.');
+
+        let (nonce, ciphertext) = match (parts.next(), parts.next()) {
+            (Some(n), Some(c)) => (base64::decode(n)?, base64::decode(c)?),
+            _ => failure::bail!("invalid encrypted token"),
+        };
+
+        let nonce = Nonce::try_assume_unique_for_key(&nonce).map_err(|_| failure::format_err!("invalid token nonce"))?;
+        let mut in_out = ciphertext;
+
+        let plaintext = self.aead_key()?
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .map_err(|_| failure::format_err!("could not decrypt token, wrong key?"))?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(Error::from)
+    }
+}
+
+// Prefix of values encrypted with `Keyring::seal`, values without it were sealed directly with a `TokenKey`
+const ENVELOPE_V1: &str = "v1";
+
+// Key encryption keys by id. Values are sealed with a random data key, which is in turn sealed
+// with the current key and saved next to the value as `v1:<key id>:<sealed data key>:<sealed value>`.
+// Old keys are only used to open values sealed before a rotation
+pub struct Keyring {
+    current_id: String,
+    keys: HashMap<String, TokenKey>,
+}
+
+impl Keyring {
+    pub fn new(current_id: &str, current: TokenKey) -> Keyring {
+        let mut keys = HashMap::new();
+        keys.insert(current_id.to_owned(), current);
+        Keyring { current_id: current_id.to_owned(), keys }
+    }
+
+    pub fn with_old_key(mut self, id: &str, key: TokenKey) -> Keyring {
+        self.keys.entry(id.to_owned()).or_insert(key);
+        self
+    }
+
+    pub fn current_id(&self) -> &str {
+        &self.current_id
+    }
+
+    fn current(&self) -> &TokenKey {
+        &self.keys[&self.current_id]
+    }
+
+    pub fn seal(&self, plaintext: &str) -> Result<String, Error> {
+        let data_key = random_bytes(AES_256_GCM.key_len())?;
+        let sealed_key = self.current().seal(&base64::encode(&data_key))?;
+        let sealed = TokenKey(data_key).seal(plaintext)?;
+
+        Ok(format!("{}:{}:{}:{}", ENVELOPE_V1, self.current_id, sealed_key, sealed))
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<String, Error> {
+        let parts: Vec<&str> = sealed.splitn(4, ':').collect();
+
+        match parts.as_slice() {
+            [ENVELOPE_V1, key_id, sealed_key, sealed_value] => {
+                let key = self.keys.get(*key_id)
+                    .ok_or_else(|| failure::format_err!("unknown token key id: {}", key_id))?;
+
+                let data_key = base64::decode(&key.open(sealed_key)?)?;
+                TokenKey(data_key).open(sealed_value)
+            },
+            // sealed with one of the keys, before key ids were saved
+            _ => self.keys.values()
+                .find_map(|key| key.open(sealed).ok())
+                .ok_or_else(|| failure::format_err!("could not decrypt token with any key")),
+        }
+    }
+
+    // true if `sealed` should be sealed again with the current key
+    pub fn is_outdated(&self, sealed: &str) -> bool {
+        !sealed.starts_with(&format!("{}:{}:", ENVELOPE_V1, self.current_id))
+    }
+}
+
+// `None` if no `token_key` is configured, tokens are then saved in plain text
+pub fn keyring_from_config(config: &Config) -> Result<Option<Keyring>, Error> {
+    let current = match config.token_key() {
+        Some(key) => TokenKey::from_base64(key)?,
+        None => return Ok(None),
+    };
+
+    let mut keyring = Keyring::new(config.token_key_id(), current);
+
+    for (id, key) in config.old_token_keys() {
+        keyring = keyring.with_old_key(id, TokenKey::from_base64(key)?);
+    }
+
+    Ok(Some(keyring))
+}
+
+// Signs download links so a backup can be downloaded without the owner's session until the link expires
+#[derive(Clone)]
+pub struct LinkKey(hmac::Key);
+
+impl LinkKey {
+    // Derived so the secret itself is not used for two different things
+    pub fn derive(secret: &[u8]) -> LinkKey {
+        let derived = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), b"spotify-backup download links");
+        LinkKey(hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref()))
+    }
+
+    fn message(id: Uuid, expires: i64) -> String {
+        format!("{}:{}", id, expires)
+    }
+
+    // `expires` is a unix timestamp
+    pub fn sign(&self, id: Uuid, expires: i64) -> String {
+        let tag = hmac::sign(&self.0, Self::message(id, expires).as_bytes());
+        base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn verify(&self, id: Uuid, expires: i64, signature: &str, now: i64) -> bool {
+        if expires < now {
+            return false;
+        }
+
+        match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(tag) => hmac::verify(&self.0, Self::message(id, expires).as_bytes(), &tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn test_seal_and_open() -> Result<(), Error> {
+        let key = TokenKey::from_base64(KEY)?;
+        let sealed = key.seal("my refresh token")?;
+
+        assert!(!sealed.contains("my refresh token"));
+        assert_eq!(key.open(&sealed)?, "my refresh token");
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_fails_with_other_key() -> Result<(), Error> {
+        let sealed = TokenKey::from_base64(KEY)?.seal("my refresh token")?;
+        let other = TokenKey::from_base64(&base64::encode(&[7u8; 32]))?;
+
+        assert!(other.open(&sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verifies_signed_links() {
+        let key = LinkKey::derive(&[1u8; 32]);
+        let id = Uuid::new_v4();
+        let signature = key.sign(id, 1000);
+
+        assert!(key.verify(id, 1000, &signature, 999));
+        assert!(!key.verify(id, 1000, &signature, 1001));
+        assert!(!key.verify(id, 2000, &signature, 999));
+        assert!(!key.verify(Uuid::new_v4(), 1000, &signature, 999));
+        assert!(!LinkKey::derive(&[2u8; 32]).verify(id, 1000, &signature, 999));
+    }
+
+    fn other_key() -> TokenKey {
+        TokenKey::from_base64(&base64::encode(&[7u8; 32])).unwrap()
+    }
+
+    #[test]
+    fn test_keyring_seal_and_open() -> Result<(), Error> {
+        let keyring = Keyring::new("k1", TokenKey::from_base64(KEY)?);
+        let sealed = keyring.seal("my access token")?;
+
+        assert!(sealed.starts_with("v1:k1:"));
+        assert!(!sealed.contains("my access token"));
+        assert_eq!(keyring.open(&sealed)?, "my access token");
+        assert!(!keyring.is_outdated(&sealed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyring_opens_values_sealed_with_old_keys() -> Result<(), Error> {
+        let old = Keyring::new("k1", TokenKey::from_base64(KEY)?);
+        let sealed = old.seal("my access token")?;
+        let legacy = TokenKey::from_base64(KEY)?.seal("my refresh token")?;
+
+        let rotated = Keyring::new("k2", other_key()).with_old_key("k1", TokenKey::from_base64(KEY)?);
+
+        assert!(rotated.is_outdated(&sealed));
+        assert!(rotated.is_outdated(&legacy));
+        assert_eq!(rotated.open(&sealed)?, "my access token");
+        assert_eq!(rotated.open(&legacy)?, "my refresh token");
+
+        assert!(Keyring::new("k2", other_key()).open(&sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_short_keys() {
+        assert!(TokenKey::from_base64(&base64::encode(b"too short")).is_err());
+    }
+}
@@ -1,50 +1,356 @@
 use r2d2_sqlite::SqliteConnectionManager;
+use actix_web::{App, HttpServer, web};
 use std::thread;
 use failure::Error;
 
 use super::db;
 use crate::serialize::Backup;
+use crate::progress::{Cancelled, Progress};
 use crate::backup_fn::*;
 use crate::backup_fn::BackupFn;
 use crate::config::Config;
 use std::path::PathBuf;
-use std::fs::File;
-use std::io::prelude::*;
-use crate::server::db::BackupRequest;
+use crate::server::db::{BackupRequest, BackupSchedule, RequestStatus};
+use crate::server::crypto::{self, Keyring};
+use crate::server::store::{build_store, BackupStore, NotFound};
+use crate::spotify::build_spotify_oauth;
+use crate::options::{BackupOptions, Collection};
+use rspotify::spotify::oauth2::TokenInfo;
+use rspotify::spotify::client::ApiError;
 use uuid::Uuid;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::RefCell;
+use crate::metrics;
+use crate::logging;
 use log;
 
-fn write_backup(req: &BackupRequest, backup: &Backup, backups_dir: &PathBuf) -> Result<PathBuf, Error> {
-    let path = PathBuf::from(format!("{}/{}.json", backups_dir.display(), req.id));
-    let mut file = File::create(&path)?;
+// Returns the key the backup was saved with and its size. Keyed by claim so a worker that lost
+// its claim never overwrites, or deletes, the file of the worker that claimed the request again
+fn write_backup(req: &BackupRequest, claim_id: Uuid, backup: &Backup, store: &dyn BackupStore) -> Result<(PathBuf, i64), Error> {
+    let key = format!("{}-{}.json", req.id, claim_id);
     let json = serde_json::to_string(backup)?;
-    file.write_all(json.as_bytes())?;
-    Ok(path)
+    store.put(&key, json.as_bytes())?;
+    Ok((PathBuf::from(key), json.len() as i64))
 }
 
-fn process_backup_request(pool: db::Pool, backup_fn: impl BackupFn, req: &BackupRequest, backups_dir: &PathBuf) -> Result<Uuid, Error> {
+// Returned by the progress callback when the daemon is stopping, the backup is put back in the queue
+#[derive(Debug)]
+struct ShuttingDown;
+
+impl std::fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "daemon is shutting down")
+    }
+}
+
+impl failure::Fail for ShuttingDown {}
+
+// Returned when another worker claimed the backup meanwhile, eg. this worker's lease expired
+#[derive(Debug)]
+struct LostClaim;
+
+impl std::fmt::Display for LostClaim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "lost the claim on the backup")
+    }
+}
+
+impl failure::Fail for LostClaim {}
+
+#[derive(Debug)]
+struct PendingTimeout;
+
+impl std::fmt::Display for PendingTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pending backup is too old")
+    }
+}
+
+impl failure::Fail for PendingTimeout {}
+
+// How often idle workers write a heartbeat, busy workers write one every time they renew their lease
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Sleeps in short steps so threads notice a shutdown without waiting for the full duration
+fn sleep_unless_shutdown(shutdown: &AtomicBool, duration: std::time::Duration) {
+    let step = std::time::Duration::from_millis(100);
+    let mut slept = std::time::Duration::from_secs(0);
+
+    while slept < duration && !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(step);
+        slept += step;
+    }
+}
+
+// One of the threads taking backups, in any daemon process
+pub struct Worker {
+    id: String,
+    pending_timeout: time::Duration,
+    lease: time::Duration,
+    max_attempts: u32,
+    retry_backoff: time::Duration,
+    shutdown: Arc<AtomicBool>,
+    // decrypts the tokens of claimed backups, `None` if tokens are saved in plain text
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl Worker {
+    pub fn new(id: String, config: &Config, shutdown: Arc<AtomicBool>, keyring: Option<Arc<Keyring>>) -> Worker {
+        Worker {
+            id,
+            pending_timeout: config.pending_timeout(),
+            lease: config.job_lease(),
+            max_attempts: config.max_attempts,
+            retry_backoff: config.retry_backoff(),
+            shutdown,
+            keyring,
+        }
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    // Lets the server know this worker is alive, see `server::health`
+    fn heartbeat(&self, pool: &db::Pool) {
+        save_heartbeat(pool, &self.id)
+    }
+
+    // Doubles after every failed attempt
+    fn retry_delay(&self, attempts: u32) -> time::Duration {
+        self.retry_backoff * 2i32.pow(attempts.saturating_sub(1).min(10))
+    }
+}
+
+fn save_heartbeat(pool: &db::Pool, worker_id: &str) {
+    let saved = pool.get().map_err(Error::from)
+        .and_then(|c| db::heartbeat::beat(c, worker_id, time::get_time()));
+
+    if let Err(err) = saved {
+        log::warn!("Could not save heartbeat for worker {}: {}", worker_id, err)
+    }
+}
+
+// Renews the lease on a claimed backup from its own thread every third of the lease, so it does not
+// depend on how often collectors report progress, rate limit waits or how long the upload takes
+struct LeaseRenewal {
+    stop: Arc<AtomicBool>,
+    lost: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LeaseRenewal {
+    fn start(pool: db::Pool, worker: &Worker, id: Uuid, claim_id: Uuid) -> LeaseRenewal {
+        let stop = Arc::new(AtomicBool::new(false));
+        let lost = Arc::new(AtomicBool::new(false));
+        let lease = worker.lease;
+        let worker_id = worker.id.clone();
+        let interval = std::time::Duration::from_millis((lease.num_milliseconds() / 3).max(100) as u64);
+
+        let (thread_stop, thread_lost) = (stop.clone(), lost.clone());
+
+        let thread = thread::Builder::new().name(format!("lease-{}", worker_id)).spawn(move || {
+            loop {
+                sleep_unless_shutdown(&thread_stop, interval);
+
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                save_heartbeat(&pool, &worker_id);
+
+                match pool.get().map_err(Error::from).and_then(|c| db::backup_request::renew_lease(c, id, claim_id, lease)) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        log::warn!("Lost the claim on backup {}", id);
+                        thread_lost.store(true, Ordering::SeqCst);
+                        break;
+                    },
+                    Err(err) => log::warn!("Could not renew lease for backup {}: {}", id, err),
+                }
+            }
+        }).expect("Could not start lease renewal thread");
+
+        LeaseRenewal { stop, lost, thread: Some(thread) }
+    }
+
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for LeaseRenewal {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Failures worth retrying later, eg. spotify being down or the network. Anything else, like
+// a revoked token, fails the same way every time
+fn is_transient(err: &Error) -> bool {
+    if let Some(api_err) = err.downcast_ref::<ApiError>() {
+        return match api_err {
+            ApiError::RateLimited(_) => true,
+            ApiError::Other(status) => *status >= 500,
+            ApiError::Unauthorized => false,
+        };
+    }
+
+    if let Some(http_err) = err.downcast_ref::<reqwest::Error>() {
+        return http_err.status().map(|s| s.is_server_error()).unwrap_or(true);
+    }
+
+    err.downcast_ref::<std::io::Error>().is_some() || err.downcast_ref::<r2d2::Error>().is_some()
+}
+
+// Label for `metrics::BACKUPS_FAILED`
+fn failure_reason(err: &Error) -> &'static str {
+    if err.downcast_ref::<PendingTimeout>().is_some() {
+        return "pending_timeout";
+    }
+
+    if let Some(api_err) = err.downcast_ref::<ApiError>() {
+        return match api_err {
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Other(_) => "spotify_error",
+        };
+    }
+
+    if err.downcast_ref::<reqwest::Error>().is_some() {
+        "network"
+    } else if err.downcast_ref::<std::io::Error>().is_some() {
+        "storage"
+    } else if err.downcast_ref::<r2d2::Error>().is_some() || err.downcast_ref::<rusqlite::Error>().is_some() {
+        "database"
+    } else {
+        "other"
+    }
+}
+
+fn process_backup_request(pool: db::Pool, backup_fn: impl BackupFn, req: &BackupRequest, claim_id: Uuid, store: &dyn BackupStore, worker: &Worker) -> Result<Uuid, Error> {
     log::info!("Starting backup {}", req.id);
 
-    if req.time_created < (time::now() - time::Duration::hours(1)).to_timespec() {
+    // retries are late on purpose
+    if req.attempts <= 1 && req.time_created < time::get_time() - worker.pending_timeout {
         log::warn!("Pending backup is too old, setting error");
-        failure::bail!("pending backup is too old")
+        Err(PendingTimeout.into())
     } else {
-        let backup = backup_fn.apply(req.token.clone())?;
-        let file = write_backup(&req, &backup, backups_dir)?;
-        db::backup_request::set_executed(pool.get()?, req.id, &file)?;
-        log::info!("Completed backup {} saved to {:?}", req.id, file);
+        let started = std::time::Instant::now();
+        let renewal = LeaseRenewal::start(pool.clone(), worker, req.id, claim_id);
+        let last_progress: RefCell<Option<Progress>> = RefCell::new(None);
+
+        let on_progress = |progress: &Progress| -> Result<(), Error> {
+            // progress is cumulative per collection, only count what was fetched since the last update
+            let fetched = match last_progress.replace(Some(progress.clone())) {
+                Some(last) if last.collection == progress.collection => progress.fetched.saturating_sub(last.fetched),
+                _ => progress.fetched,
+            };
+            metrics::ITEMS_FETCHED.with_label_values(&[&progress.collection]).inc_by(fetched as i64);
+
+            if worker.is_stopping() {
+                return Err(ShuttingDown.into());
+            }
+
+            if renewal.is_lost() {
+                return Err(LostClaim.into());
+            }
+
+            let status = pool.get().map_err(Error::from)
+                .and_then(|c| db::backup_request::find_status(c, req.id));
+
+            if let Ok(Some(RequestStatus::Cancelled)) = status {
+                return Err(Cancelled.into());
+            }
+
+            let saved = pool.get().map_err(Error::from)
+                .and_then(|c| db::backup_request::set_progress(c, req.id, progress));
+
+            if let Err(err) = saved {
+                log::warn!("Could not save progress for backup {}: {}", req.id, err)
+            }
+
+            Ok(())
+        };
+
+        let backup = backup_fn.apply(req.token.clone(), &req.options, &on_progress)?;
+
+        if renewal.is_lost() {
+            return Err(LostClaim.into());
+        }
+
+        let (file, file_size) = write_backup(&req, claim_id, &backup, store)?;
+        let warning_count = backup.errors.len() as u32;
+
+        if let Err(err) = db::backup_request::set_executed(pool.get()?, req.id, claim_id, &file, warning_count, file_size) {
+            // eg. cancelled or claimed by another worker while the file was saved, nothing points to the file
+            if let Err(delete_err) = store.delete(&file.to_string_lossy()) {
+                log::warn!("Could not delete backup file {:?}: {}", file, delete_err)
+            }
+
+            return match db::backup_request::find_status(pool.get()?, req.id)? {
+                Some(RequestStatus::Cancelled) => Err(Cancelled.into()),
+                Some(RequestStatus::Running) | Some(RequestStatus::Executed) => Err(LostClaim.into()),
+                _ => Err(err),
+            };
+        }
+
+        metrics::BACKUPS_COMPLETED.inc();
+        metrics::BACKUP_DURATION.observe(started.elapsed().as_secs_f64());
+        metrics::BACKUP_FILE_SIZE.observe(file_size as f64);
+
+        log::info!("Completed backup {} saved to {:?} with {} warnings", req.id, file, warning_count);
         Ok(req.id)
     }
 }
 
-fn process_oldest_backup_request(pool: db::Pool, backup_fn: impl BackupFn, backups_dir: &PathBuf, thread_id: u32, total_thread_count: u32) -> Result<Option<Uuid>,Error> {
-    match db::backup_request::oldest_pending(pool.get()?, thread_id, total_thread_count) {
+fn process_oldest_backup_request(pool: db::Pool, backup_fn: impl BackupFn, store: &dyn BackupStore, worker: &Worker) -> Result<Option<Uuid>,Error> {
+    let claim_id = Uuid::new_v4();
+
+    match db::backup_request::claim_next(pool.get()?, worker.keyring.as_deref(), &worker.id, claim_id, worker.lease) {
         Ok(Some(req)) => {
-            log::info!("Found new backup request {}", req.id);
+            let _log_scope = logging::BackupScope::enter(req.id, req.user_id.clone(), req.request_id.clone());
+            log::info!("Claimed backup request {} on worker {}", req.id, worker.id);
+            metrics::BACKUPS_STARTED.inc();
+
+            if let Err(err) = process_backup_request(pool.clone(), backup_fn, &req, claim_id, store, worker) {
+                if err.downcast_ref::<Cancelled>().is_some() {
+                    log::info!("Stopped cancelled backup {}", req.id);
+                    metrics::BACKUPS_STOPPED.with_label_values(&["cancelled"]).inc();
+                    return Ok(Some(req.id));
+                }
+
+                if err.downcast_ref::<LostClaim>().is_some() {
+                    log::warn!("Stopped backup {}, it was claimed by another worker", req.id);
+                    metrics::BACKUPS_STOPPED.with_label_values(&["lost_claim"]).inc();
+                    return Ok(Some(req.id));
+                }
+
+                if err.downcast_ref::<ShuttingDown>().is_some() {
+                    log::info!("Releasing backup {} back to the queue, worker {} is stopping", req.id, worker.id);
+                    metrics::BACKUPS_STOPPED.with_label_values(&["shutdown"]).inc();
+                    db::backup_request::release(pool.get()?, req.id, claim_id)?;
+                    return Ok(Some(req.id));
+                }
 
-            if let Err(err) = process_backup_request(pool.clone(), backup_fn, &req, backups_dir) {
-                if let Err(save_err) = db::backup_request::set_error(pool.get()?, req.id, &format!("{}", err)) {
-                    log::error!("Could not set error on backup request: {}", save_err)
+                metrics::BACKUPS_FAILED.with_label_values(&[failure_reason(&err)]).inc();
+
+                let saved = if is_transient(&err) && req.attempts < worker.max_attempts {
+                    let next_attempt_at = time::get_time() + worker.retry_delay(req.attempts);
+                    log::warn!("Backup {} failed on attempt {}/{}, retrying at {}: {}",
+                               req.id, req.attempts, worker.max_attempts, time::at_utc(next_attempt_at).rfc3339(), err);
+                    db::backup_request::set_retry(pool.get()?, req.id, claim_id, next_attempt_at)
+                } else {
+                    db::backup_request::set_error(pool.get()?, req.id, claim_id, &format!("{}", err))
+                };
+
+                if let Err(save_err) = saved {
+                    log::error!("Could not save failed backup request: {}", save_err)
                 }
                 Err(err)
             } else {
@@ -58,60 +364,230 @@ fn process_oldest_backup_request(pool: db::Pool, backup_fn: impl BackupFn, backu
     }
 }
 
-fn delete_executed(pool: db::Pool) -> Result<Vec<Uuid>, Error> {
-    let executed = db::backup_request::find_executed(pool.get()?)?;
+fn delete_executed(pool: db::Pool, keyring: Option<&Keyring>, store: &dyn BackupStore, retention: time::Duration, scheduled_backups_kept: u32, batch_size: u32) -> Result<Vec<Uuid>, Error> {
+    let mut executed = db::backup_request::find_executed(pool.get()?, keyring, retention, batch_size)?;
+    executed.extend(db::backup_request::find_expired_scheduled(pool.get()?, keyring, scheduled_backups_kept, batch_size)?);
+
+    let mut deleted = vec![];
 
     for req in executed.iter() {
         log::info!("Found executed backup {}", req.id);
 
         if let Some(file) = &req.file {
-            std::fs::remove_file(file)?;
-            log::info!("deleted expired backup file: {:?}", file);
+            match store.delete(&file.to_string_lossy()) {
+                Ok(()) => log::info!("deleted expired backup file: {:?}", file),
+                Err(err) if err.downcast_ref::<NotFound>().is_some() => log::info!("expired backup file {:?} was already deleted", file),
+                Err(err) => {
+                    // Stays executed, the next sweep tries again
+                    log::error!("Could not delete file {:?} of expired backup {}: {}", file, req.id, err);
+                    continue;
+                }
+            }
         }
 
         db::backup_request::set_completed(pool.get()?, req.id, req.last_error.is_some())?;
+        deleted.push(req.id);
     }
 
-    Ok(executed.into_iter().map(|e| e.id).collect())
+    Ok(deleted)
+}
+
+fn enqueue_schedule_run(pool: db::Pool, key: &Keyring, refresh: &dyn Fn(&str) -> Option<TokenInfo>, schedule: &BackupSchedule) -> Result<Uuid, Error> {
+    let refresh_token = key.open(&schedule.refresh_token)?;
+
+    let mut token = refresh(&refresh_token)
+        .ok_or_else(|| failure::format_err!("could not refresh spotify token"))?;
+
+    // Spotify might send us a new refresh token, the old one stops working.
+    // Either way the refresh token is only saved, encrypted, with the schedule
+    if let Some(new_refresh_token) = token.refresh_token.take() {
+        if new_refresh_token != refresh_token {
+            db::backup_schedule::set_refresh_token(pool.get()?, schedule.id, &key.seal(&new_refresh_token)?)?;
+        }
+    }
+
+    let req = BackupRequest {
+        id: Uuid::new_v4(),
+        token,
+        time_created: time::get_time(),
+        file: None,
+        last_error: None,
+        warning_count: 0,
+        progress: None,
+        options: schedule.options.clone(),
+        schedule_id: Some(schedule.id),
+        user_id: Some(schedule.user_id.clone()),
+        file_size: None,
+        expires_at: None,
+        attempts: 0,
+        next_attempt_at: None,
+        request_id: None,
+    };
+
+    db::backup_request::create(pool.get()?, Some(key), &req)
+}
+
+fn enqueue_scheduled(pool: db::Pool, key: &Keyring, refresh: &dyn Fn(&str) -> Option<TokenInfo>) -> Result<Vec<Uuid>, Error> {
+    let now = time::get_time();
+    let due = db::backup_schedule::find_due(pool.get()?, now)?;
+    let mut enqueued = vec![];
+
+    for schedule in due.iter() {
+        let mut next_run_at = schedule.next_run_at + schedule.frequency.interval();
+
+        // Do not enqueue every run missed while the daemon was not running
+        if next_run_at <= now {
+            next_run_at = now + schedule.frequency.interval();
+        }
+
+        // Claimed before refreshing the token, so a daemon that found the same schedule skips it
+        if !db::backup_schedule::set_next_run(pool.get()?, schedule.id, schedule.next_run_at, next_run_at)? {
+            log::info!("Schedule {} was already enqueued by another worker", schedule.id);
+            continue;
+        }
+
+        match enqueue_schedule_run(pool.clone(), key, refresh, schedule) {
+            Ok(id) => {
+                log::info!("Enqueued backup {} for schedule {}", id, schedule.id);
+                db::backup_schedule::set_last_error(pool.get()?, schedule.id, None)?;
+                enqueued.push(id);
+            },
+            Err(err) => {
+                log::warn!("Could not enqueue backup for schedule {}: {}", schedule.id, err);
+                db::backup_schedule::set_last_error(pool.get()?, schedule.id, Some(&format!("{}", err)))?;
+            }
+        }
+    }
+
+    Ok(enqueued)
+}
+
+// Runs its own actix system in a separate thread. It is not joined on shutdown, it stops with the process
+fn serve_metrics(addr: String, pool: db::Pool) {
+    thread::spawn(move || {
+        let sys = actix_rt::System::new("daemon-metrics");
+
+        let bound = HttpServer::new(move || App::new().data(pool.clone()).route("/metrics", web::get().to(super::metrics)))
+            .disable_signals()
+            .bind(&addr);
+
+        match bound {
+            Ok(server) => {
+                log::info!("Serving daemon metrics on {}", addr);
+                let _server = server.run();
+
+                if let Err(err) = sys.run() {
+                    log::error!("Daemon metrics listener stopped: {}", err);
+                }
+            },
+            Err(err) => log::error!("Could not serve daemon metrics on {}: {}", addr, err),
+        }
+    });
 }
 
 pub fn daemon(config: Config) -> () {
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    for signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+        signal_hook::flag::register(*signal, shutdown.clone()).expect("Could not register signal handler");
+    }
+
     let manager = SqliteConnectionManager::file(&config.db_path());
     let pool = db::Pool::new(manager).unwrap();
+    super::migrations::run(&pool).expect("Could not migrate database");
+
+    let store = build_store(&config).expect("Could not build backup store");
+
+    // keys are checked by `Config::validate`
+    let keyring = crypto::keyring_from_config(&config).expect("Could not load token keys").map(Arc::new);
+
+    if let Some(addr) = config.daemon_metrics_addr.clone() {
+        serve_metrics(addr, pool.clone());
+    }
 
     let num_threads = config.worker_count;
     let mut workers = vec![];
 
     for tid in 0..num_threads {
-        log::info!("Starting thread {}/{}", tid, num_threads);
+        // unique across daemons sharing the database, only used to see who claimed a backup
+        let worker = Worker::new(format!("{}-{}", std::process::id(), tid), &config, shutdown.clone(), keyring.clone());
+        let thread_name = format!("worker-{}", worker.id);
+        log::info!("Starting worker {} ({}/{})", worker.id, tid, num_threads);
 
         let pool = pool.clone();
-        let backups_dir = config.downloads_path().clone();
+        let store = store.clone();
+
+        workers.push(thread::Builder::new().name(thread_name).spawn(move || {
+            let mut last_heartbeat: Option<std::time::Instant> = None;
+
+            while !worker.is_stopping() {
+                if last_heartbeat.map(|at| at.elapsed() >= HEARTBEAT_INTERVAL).unwrap_or(true) {
+                    worker.heartbeat(&pool);
+                    last_heartbeat = Some(std::time::Instant::now());
+                }
 
-        workers.push(thread::spawn(move || {
-            loop {
                 let pool = pool.clone();
 
-                match process_oldest_backup_request(pool, DefaultBackup, &backups_dir, tid, num_threads) {
+                match process_oldest_backup_request(pool, DefaultBackup, store.as_ref(), &worker) {
                     Ok(Some(id)) => {
                         log::info!("Finished backup processing for {}", id);
                     },
                     Ok(None) => {
                         log::debug!("No pending backups, checking later");
-                        thread::sleep(std::time::Duration::from_secs(1))
+                        sleep_unless_shutdown(&worker.shutdown, std::time::Duration::from_secs(1))
                     },
                     Err(err) => {
                         log::error!("Could not process backup: {}, {:?}", err, err);
-                        thread::sleep(std::time::Duration::from_secs(5));
+                        sleep_unless_shutdown(&worker.shutdown, std::time::Duration::from_secs(5));
                     }
                 }
             }
-        }));
+
+            if let Err(err) = pool.get().map_err(Error::from).and_then(|c| db::heartbeat::delete(c, &worker.id)) {
+                log::warn!("Could not delete heartbeat for worker {}: {}", worker.id, err)
+            }
+
+            log::info!("Stopped worker {}", worker.id);
+        }).expect("Could not start worker thread"));
     }
 
+    let scheduler_thread = match keyring.clone() {
+        Some(key) => {
+            let scope = BackupOptions::new(Collection::all()).scope();
+            let oauth = build_spotify_oauth(config.client_id(), config.client_secret(), &config.base_uri().to_string(), config.token_cache_path(), &scope);
+            let pool = pool.clone();
+            let shutdown = shutdown.clone();
+
+            Some(thread::spawn(move || {
+                let refresh = |refresh_token: &str| crate::spotify::refresh_access_token(&oauth, refresh_token);
+
+                while !shutdown.load(Ordering::SeqCst) {
+                    match enqueue_scheduled(pool.clone(), &key, &refresh) {
+                        Ok(enqueued) =>
+                            log::debug!("Enqueued {} scheduled backups", enqueued.len()),
+                        Err(err) =>
+                            log::error!("Could not enqueue scheduled backups: {}, {:?}", err, err)
+                    }
+
+                    sleep_unless_shutdown(&shutdown, std::time::Duration::from_secs(60));
+                }
+            }))
+        },
+        None => {
+            log::info!("token_key is not set, recurring backups are disabled");
+            None
+        }
+    };
+
+    let scheduled_backups_kept = config.scheduled_backups_kept;
+    let default_retention = time::Duration::hours(config.retention_limits().default_hours as i64);
+    let sweep_batch_size = config.sweep_batch_size;
+
+    let cleanup_shutdown = shutdown.clone();
+
     let cleanup_thread = thread::spawn(move || {
-        loop {
-            match delete_executed(pool.clone()) {
+        while !cleanup_shutdown.load(Ordering::SeqCst) {
+            match delete_executed(pool.clone(), keyring.as_deref(), store.as_ref(), default_retention, scheduled_backups_kept, sweep_batch_size) {
                 Ok(_) =>
                     log::debug!("Processed executed/error backups"),
                 Err(err) => {
@@ -119,7 +595,7 @@ pub fn daemon(config: Config) -> () {
                 }
             }
 
-            thread::sleep(std::time::Duration::from_secs(5));
+            sleep_unless_shutdown(&cleanup_shutdown, std::time::Duration::from_secs(5));
         }
     });
 
@@ -128,6 +604,12 @@ pub fn daemon(config: Config) -> () {
     }
 
     cleanup_thread.join().unwrap();
+
+    if let Some(scheduler) = scheduler_thread {
+        scheduler.join().unwrap();
+    }
+
+    log::info!("Daemon stopped");
 }
 
 
@@ -135,24 +617,104 @@ pub fn daemon(config: Config) -> () {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rspotify::spotify::oauth2::TokenInfo;
-    use db::backup_request::tests::new_db;
+    use db::backup_request::tests::{claimed, new_db};
+    use db::backup_schedule::tests::new_schedule;
     use tempfile::tempdir;
     use lazy_static::lazy_static;
     use crate::server::db::backup_request::{find_executed, find_with_status};
+    use crate::serialize::BackupItemError;
+    use crate::server::store::LocalStore;
+    use crate::server::crypto::TokenKey;
 
     lazy_static! {
       static ref TEST_BACKUP_DIR: PathBuf = tempdir().unwrap().into_path();
     }
 
+    fn test_store() -> LocalStore {
+        LocalStore::new(TEST_BACKUP_DIR.clone())
+    }
+
+    fn backup_files(id: Uuid) -> Result<Vec<PathBuf>, Error> {
+        let mut files = vec![];
+
+        for entry in std::fs::read_dir(TEST_BACKUP_DIR.as_path())? {
+            let path = entry?.path();
+
+            if path.file_name().unwrap_or_default().to_string_lossy().starts_with(&id.to_string()) {
+                files.push(path);
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn test_worker(pending_timeout: time::Duration) -> Worker {
+        Worker {
+            id: "test-worker".to_owned(),
+            pending_timeout,
+            lease: time::Duration::minutes(5),
+            max_attempts: 3,
+            retry_backoff: time::Duration::minutes(1),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            keyring: None,
+        }
+    }
+
     struct EmptyBackup;
 
     impl BackupFn for EmptyBackup {
-        fn apply(&self, _token_info: TokenInfo) -> Result<Backup, Error> {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, _progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            Ok(
+                Backup {
+                    collections: vec![],
+                    albums: vec![],
+                    playlists: vec![],
+                    liked_songs: vec![],
+                    followed_artists: vec![],
+                    errors: vec![],
+                }
+            )
+        }
+    }
+
+    struct ProgressBackup;
+
+    impl BackupFn for ProgressBackup {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            progress(&Progress::new("albums", 10, 20))?;
+
             Ok(
                 Backup {
+                    collections: vec![],
                     albums: vec![],
-                    playlists: vec![]
+                    playlists: vec![],
+                    liked_songs: vec![],
+                    followed_artists: vec![],
+                    errors: vec![],
+                }
+            )
+        }
+    }
+
+    struct PartialBackup;
+
+    impl BackupFn for PartialBackup {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, _progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            let error = BackupItemError {
+                collection: "playlists".into(),
+                id: "playlist-id-01".into(),
+                name: None,
+                message: "[test] playlist not found".into(),
+            };
+
+            Ok(
+                Backup {
+                    collections: vec![],
+                    albums: vec![],
+                    playlists: vec![],
+                    liked_songs: vec![],
+                    followed_artists: vec![],
+                    errors: vec![error],
                 }
             )
         }
@@ -161,11 +723,51 @@ mod tests {
     struct ErrorBackup;
 
     impl BackupFn for ErrorBackup {
-        fn apply(&self, _token_info: TokenInfo) -> Result<Backup, Error> {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, _progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
             failure::bail!("[test] error backup")
         }
     }
 
+    struct UnavailableBackup;
+
+    impl BackupFn for UnavailableBackup {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, _progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            Err(ApiError::Other(503).into())
+        }
+    }
+
+    // Cancelled by the user while fetching the first page
+    struct CancelledBackup(db::Pool, Uuid);
+
+    impl BackupFn for CancelledBackup {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            db::backup_request::cancel(self.0.get()?, self.1)?;
+            progress(&Progress::new("albums", 10, 20))?;
+            EmptyBackup.apply(TokenInfo::default(), &BackupOptions::default(), progress)
+        }
+    }
+
+    // The daemon is asked to stop while fetching the first page
+    struct InterruptedBackup(Arc<AtomicBool>);
+
+    impl BackupFn for InterruptedBackup {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            self.0.store(true, Ordering::SeqCst);
+            progress(&Progress::new("albums", 10, 20))?;
+            EmptyBackup.apply(TokenInfo::default(), &BackupOptions::default(), progress)
+        }
+    }
+
+    // Another worker claims the backup while this one is still fetching it
+    struct StolenBackup(db::Pool, Uuid);
+
+    impl BackupFn for StolenBackup {
+        fn apply(&self, _token_info: TokenInfo, _options: &BackupOptions, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Backup, Error> {
+            claimed(&self.0, self.1)?;
+            EmptyBackup.apply(TokenInfo::default(), &BackupOptions::default(), progress)
+        }
+    }
+
     fn new_req() -> BackupRequest {
         BackupRequest {
             id: Uuid::new_v4(),
@@ -173,6 +775,16 @@ mod tests {
             time_created: time::get_time(),
             file: None,
             last_error: None,
+            warning_count: 0,
+            progress: None,
+            options: BackupOptions::default(),
+            schedule_id: None,
+            user_id: None,
+            file_size: None,
+            expires_at: None,
+            attempts: 0,
+            next_attempt_at: None,
+            request_id: None,
         }
     }
 
@@ -180,15 +792,15 @@ mod tests {
     fn test_processes_existing_backup_request() -> Result<(), Error> {
         let pool = new_db()?;
         let req = new_req();
-        db::backup_request::create(pool.get()?, &req)?;
+        db::backup_request::create(pool.get()?, None, &req)?;
 
-        let existing = process_oldest_backup_request(pool.clone(), EmptyBackup, &TEST_BACKUP_DIR, 0, 1)?;
+        let existing = process_oldest_backup_request(pool.clone(), EmptyBackup, &test_store(), &test_worker(time::Duration::hours(1)))?;
 
         assert_eq!(existing.unwrap(), req.id);
 
         db::backup_request::tests::set_old(pool.get()?, req.id, None)?;
 
-        let all_executed = db::backup_request::find_executed(pool.get()?)?;
+        let all_executed = db::backup_request::find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         let executed = all_executed.first().unwrap();
 
         assert_eq!(executed.id, req.id);
@@ -202,15 +814,16 @@ mod tests {
     fn test_writes_backup_to_file() -> Result<(), Error> {
         let pool = new_db()?;
         let req = new_req();
-        db::backup_request::create(pool.get()?, &req)?;
+        db::backup_request::create(pool.get()?, None, &req)?;
 
-        let existing = process_backup_request(pool.clone(), EmptyBackup, &req, &TEST_BACKUP_DIR)?;
+        let claim = claimed(&pool, req.id)?;
+        let existing = process_backup_request(pool.clone(), EmptyBackup, &req, claim, &test_store(), &test_worker(time::Duration::hours(1)))?;
 
         assert_eq!(existing, req.id);
 
-        let db_saved = db::backup_request::find_with_status(pool.get()?, req.id)?.unwrap();
+        let db_saved = db::backup_request::find_with_status(pool.get()?, None, req.id)?.unwrap();
 
-        let f = std::fs::File::open(db_saved.0.file.unwrap())?;
+        let f = std::fs::File::open(TEST_BACKUP_DIR.join(db_saved.0.file.unwrap()))?;
         let s: serde_json::Value = serde_json::from_reader(f)?;
 
         let backup = s.as_object().unwrap();
@@ -221,19 +834,57 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_saves_progress_while_running() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let claim = claimed(&pool, req.id)?;
+        process_backup_request(pool.clone(), ProgressBackup, &req, claim, &test_store(), &test_worker(time::Duration::hours(1)))?;
+
+        let (saved, _) = db::backup_request::find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(saved.progress, Some(Progress::new("albums", 10, 20)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_saves_warnings_for_partial_backups() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let claim = claimed(&pool, req.id)?;
+        process_backup_request(pool.clone(), PartialBackup, &req, claim, &test_store(), &test_worker(time::Duration::hours(1)))?;
+
+        let (saved, status) = db::backup_request::find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Executed);
+        assert_eq!(saved.warning_count, 1);
+
+        let f = std::fs::File::open(TEST_BACKUP_DIR.join(saved.file.unwrap()))?;
+        let s: serde_json::Value = serde_json::from_reader(f)?;
+        let errors = s.as_object().unwrap().get("errors").unwrap().as_array().unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].get("id").unwrap(), "playlist-id-01");
+
+        Ok(())
+    }
+
     #[test]
     fn test_sets_error_if_backup_fails() -> Result<(), Error> {
         let pool = new_db()?;
         let req = new_req();
-        db::backup_request::create(pool.get()?, &req)?;
+        db::backup_request::create(pool.get()?, None, &req)?;
 
-        let res = process_oldest_backup_request(pool.clone(), ErrorBackup, &TEST_BACKUP_DIR, 0, 1);
+        let res = process_oldest_backup_request(pool.clone(), ErrorBackup, &test_store(), &test_worker(time::Duration::hours(1)));
         db::backup_request::tests::set_old(pool.get()?, req.id, None)?;
         let err_msg = format!("{}", res.err().unwrap());
 
         assert_eq!(err_msg, "[test] error backup");
 
-        let all_executed = db::backup_request::find_executed(pool.get()?)?;
+        let all_executed = db::backup_request::find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         let executed = all_executed.first().unwrap();
 
         assert_eq!(executed.id, req.id);
@@ -243,10 +894,135 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_retries_transient_errors() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let res = process_oldest_backup_request(pool.clone(), UnavailableBackup, &test_store(), &test_worker(time::Duration::hours(1)));
+        assert!(res.is_err());
+
+        let (saved, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Pending);
+        assert_eq!(saved.attempts, 1);
+        assert!(saved.last_error.is_none());
+        assert!(saved.next_attempt_at.unwrap() > time::get_time());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sets_error_after_last_attempt() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let mut worker = test_worker(time::Duration::hours(1));
+        worker.max_attempts = 2;
+        worker.retry_backoff = time::Duration::zero();
+
+        let _ = process_oldest_backup_request(pool.clone(), UnavailableBackup, &test_store(), &worker);
+        assert_eq!(find_with_status(pool.get()?, None, req.id)?.unwrap().1, db::RequestStatus::Pending);
+
+        let _ = process_oldest_backup_request(pool.clone(), UnavailableBackup, &test_store(), &worker);
+        let (saved, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Error);
+        assert_eq!(saved.attempts, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_does_not_retry_permanent_errors() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let _ = process_oldest_backup_request(pool.clone(), ErrorBackup, &test_store(), &test_worker(time::Duration::hours(1)));
+
+        let (saved, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Error);
+        assert_eq!(saved.attempts, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_doubles_retry_delay() {
+        let worker = test_worker(time::Duration::hours(1));
+
+        assert_eq!(worker.retry_delay(1), time::Duration::minutes(1));
+        assert_eq!(worker.retry_delay(3), time::Duration::minutes(4));
+    }
+
+    #[test]
+    fn test_labels_failure_reasons() {
+        assert_eq!(failure_reason(&PendingTimeout.into()), "pending_timeout");
+        assert_eq!(failure_reason(&ApiError::RateLimited(Some(2)).into()), "rate_limited");
+        assert_eq!(failure_reason(&ApiError::Other(503).into()), "spotify_error");
+        assert_eq!(failure_reason(&failure::format_err!("[test] error")), "other");
+    }
+
+    #[test]
+    fn test_stops_cancelled_backups() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let stopped = process_oldest_backup_request(pool.clone(), CancelledBackup(pool.clone(), req.id), &test_store(), &test_worker(time::Duration::hours(1)))?;
+        assert_eq!(stopped, Some(req.id));
+
+        let (saved, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Cancelled);
+        assert!(saved.file.is_none());
+        assert!(saved.last_error.is_none());
+        assert!(backup_files(req.id)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_releases_backups_on_shutdown() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let worker = test_worker(time::Duration::hours(1));
+        let released = process_oldest_backup_request(pool.clone(), InterruptedBackup(worker.shutdown.clone()), &test_store(), &worker)?;
+        assert_eq!(released, Some(req.id));
+
+        let (saved, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Pending);
+        assert_eq!(saved.attempts, 0);
+        assert!(saved.progress.is_none());
+        assert!(backup_files(req.id)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stops_backups_claimed_by_another_worker() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        let stopped = process_oldest_backup_request(pool.clone(), StolenBackup(pool.clone(), req.id), &test_store(), &test_worker(time::Duration::hours(1)))?;
+        assert_eq!(stopped, Some(req.id));
+
+        let (saved, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(status, db::RequestStatus::Running);
+        assert!(saved.file.is_none());
+        assert!(saved.last_error.is_none());
+        assert!(backup_files(req.id)?.is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn test_ok_if_no_backups() -> Result<(), Error> {
         let pool = new_db()?;
-        let existing = process_oldest_backup_request(pool, DefaultBackup, &TEST_BACKUP_DIR, 0, 1)?;
+        let existing = process_oldest_backup_request(pool, DefaultBackup, &test_store(), &test_worker(time::Duration::hours(1)))?;
         assert_eq!(existing, None);
         Ok(())
     }
@@ -255,41 +1031,106 @@ mod tests {
     fn test_deletes_success_expired_backups() -> Result<(), Error> {
         let pool = new_db()?;
         let req = new_req();
-        db::backup_request::create(pool.get()?, &req)?;
+        db::backup_request::create(pool.get()?, None, &req)?;
 
-        process_backup_request(pool.clone(), EmptyBackup, &req, &TEST_BACKUP_DIR)?;
-        let executed = db::backup_request::find_with_status(pool.get()?, req.id)?.unwrap().0;
+        let claim = claimed(&pool, req.id)?;
+        process_backup_request(pool.clone(), EmptyBackup, &req, claim, &test_store(), &test_worker(time::Duration::hours(1)))?;
+        let executed = db::backup_request::find_with_status(pool.get()?, None, req.id)?.unwrap().0;
         db::backup_request::tests::set_old(pool.get()?, req.id, None)?;
 
-        let before = find_executed(pool.get()?)?;
+        let before = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         assert_eq!(before.first().unwrap().id, req.id);
 
-        let deleted = delete_executed(pool.clone())?;
+        let deleted = delete_executed(pool.clone(), None, &test_store(), time::Duration::hours(1), 10, 5)?;
         assert_eq!(*deleted.first().unwrap(), req.id);
-        assert!(!executed.file.unwrap().exists());
+        assert!(!TEST_BACKUP_DIR.join(executed.file.unwrap()).exists());
 
-        let after = find_executed(pool.get()?)?;
+        let after = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         assert!(after.is_empty());
 
         Ok(())
     }
 
+    // Fails to delete one key, everything else goes to `test_store`
+    struct BrokenDelete(String);
+
+    impl BackupStore for BrokenDelete {
+        fn put(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+            test_store().put(key, data)
+        }
+
+        fn open(&self, key: &str) -> Result<Box<dyn std::io::Read + Send>, Error> {
+            test_store().open(key)
+        }
+
+        fn delete(&self, key: &str) -> Result<(), Error> {
+            if key == self.0 {
+                failure::bail!("[test] could not delete {}", key)
+            }
+
+            test_store().delete(key)
+        }
+
+        fn download_url(&self, _key: &str, _expires_in: std::time::Duration) -> Result<Option<String>, Error> {
+            Ok(None)
+        }
+
+        fn check_writable(&self) -> Result<(), Error> {
+            test_store().check_writable()
+        }
+    }
+
+    #[test]
+    fn test_keeps_deleting_expired_backups_if_one_fails() -> Result<(), Error> {
+        let pool = new_db()?;
+        let mut files = vec![];
+
+        for _ in 0..3 {
+            let req = new_req();
+            db::backup_request::create(pool.get()?, None, &req)?;
+
+            let claim = claimed(&pool, req.id)?;
+            process_backup_request(pool.clone(), EmptyBackup, &req, claim, &test_store(), &test_worker(time::Duration::hours(1)))?;
+            db::backup_request::tests::set_old(pool.get()?, req.id, None)?;
+
+            let file = find_with_status(pool.get()?, None, req.id)?.unwrap().0.file.unwrap();
+            files.push((req.id, file.to_string_lossy().to_string()));
+        }
+
+        // The first one is already gone, the second can't be deleted
+        test_store().delete(&files[0].1)?;
+        let store = BrokenDelete(files[1].1.clone());
+
+        let mut deleted = delete_executed(pool.clone(), None, &store, time::Duration::hours(1), 10, 5)?;
+        deleted.sort();
+        let mut expected = vec![files[0].0, files[2].0];
+        expected.sort();
+
+        assert_eq!(deleted, expected);
+        assert!(!TEST_BACKUP_DIR.join(&files[2].1).exists());
+
+        let after = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
+        assert_eq!(after.iter().map(|r| r.id).collect::<Vec<_>>(), vec![files[1].0]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_deletes_error_expired_backups() -> Result<(), Error> {
         let pool = new_db()?;
         let req = new_req();
-        db::backup_request::create(pool.get()?, &req)?;
+        db::backup_request::create(pool.get()?, None, &req)?;
 
-        let _ = process_oldest_backup_request(pool.clone(), ErrorBackup, &TEST_BACKUP_DIR, 0, 1);
+        let _ = process_oldest_backup_request(pool.clone(), ErrorBackup, &test_store(), &test_worker(time::Duration::hours(1)));
         db::backup_request::tests::set_old(pool.get()?, req.id, None)?;
 
-        let before = find_executed(pool.get()?)?;
+        let before = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         assert_eq!(before.first().unwrap().id, req.id);
 
-        let deleted = delete_executed(pool.clone())?;
+        let deleted = delete_executed(pool.clone(), None, &test_store(), time::Duration::hours(1), 10, 5)?;
         assert_eq!(*deleted.first().unwrap(), req.id);
 
-        let after = find_executed(pool.get()?)?;
+        let after = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         assert!(after.is_empty());
 
         Ok(())
@@ -299,19 +1140,123 @@ mod tests {
     fn test_does_not_run_very_old_backups() -> Result<(), Error> {
         let pool = new_db()?;
         let req = new_req();
-        db::backup_request::create(pool.get()?, &req)?;
+        db::backup_request::create(pool.get()?, None, &req)?;
 
         db::backup_request::tests::set_old(pool.get()?, req.id, Some(time::Duration::hours(2)))?;
 
-        let _ = process_oldest_backup_request(pool.clone(), EmptyBackup, &TEST_BACKUP_DIR, 0, 1);
+        let _ = process_oldest_backup_request(pool.clone(), EmptyBackup, &test_store(), &test_worker(time::Duration::hours(1)));
 
-        let (after_req, after_status) = find_with_status(pool.get()?, req.id)?.unwrap();
+        let (after_req, after_status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
         assert_eq!(after_status, db::RequestStatus::Error);
         assert_eq!(after_req.last_error.unwrap(), "pending backup is too old");
 
-        let after = find_executed(pool.get()?)?;
+        let after = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
         assert_eq!(after.first().unwrap().id, req.id);
 
         Ok(())
     }
+
+    #[test]
+    fn test_uses_configured_pending_timeout() -> Result<(), Error> {
+        let pool = new_db()?;
+        let req = new_req();
+        db::backup_request::create(pool.get()?, None, &req)?;
+
+        db::backup_request::tests::set_old(pool.get()?, req.id, Some(time::Duration::minutes(30)))?;
+
+        let _ = process_oldest_backup_request(pool.clone(), EmptyBackup, &test_store(), &test_worker(time::Duration::minutes(10)));
+
+        let (after_req, after_status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+        assert_eq!(after_status, db::RequestStatus::Error);
+        assert_eq!(after_req.last_error.unwrap(), "pending backup is too old");
+
+        Ok(())
+    }
+
+    fn test_key() -> Keyring {
+        Keyring::new("test", TokenKey::from_base64(&base64::encode(&[1u8; 32])).unwrap())
+    }
+
+    #[test]
+    fn test_enqueues_due_schedules() -> Result<(), Error> {
+        let pool = new_db()?;
+        let key = test_key();
+        let mut schedule = new_schedule();
+        schedule.refresh_token = key.seal("my refresh token")?;
+        schedule.next_run_at = time::get_time() - time::Duration::minutes(1);
+        db::backup_schedule::create(pool.get()?, &schedule)?;
+
+        let refresh = |refresh_token: &str| {
+            assert_eq!(refresh_token, "my refresh token");
+
+            Some(TokenInfo {
+                access_token: "new access token".into(),
+                refresh_token: Some("new refresh token".into()),
+                ..TokenInfo::default()
+            })
+        };
+
+        let enqueued = enqueue_scheduled(pool.clone(), &key, &refresh)?;
+        assert_eq!(enqueued.len(), 1);
+
+        let (req, status) = find_with_status(pool.get()?, Some(&key), enqueued[0])?.unwrap();
+        assert_eq!(status, db::RequestStatus::Pending);
+        assert_eq!(req.schedule_id, Some(schedule.id));
+        assert_eq!(req.token.access_token, "new access token");
+        assert_eq!(req.token.refresh_token, None);
+
+        let saved = db::backup_schedule::find(pool.get()?, schedule.id)?.unwrap();
+        assert_eq!(key.open(&saved.refresh_token)?, "new refresh token");
+        assert!(saved.next_run_at > time::get_time());
+
+        assert!(enqueue_scheduled(pool.clone(), &key, &refresh)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_enqueues_due_schedule_once() -> Result<(), Error> {
+        let pool = new_db()?;
+        let key = test_key();
+        let mut schedule = new_schedule();
+        schedule.refresh_token = key.seal("my refresh token")?;
+        schedule.next_run_at = time::get_time() - time::Duration::minutes(1);
+        db::backup_schedule::create(pool.get()?, &schedule)?;
+
+        let token = || Some(TokenInfo { access_token: "new access token".into(), ..TokenInfo::default() });
+        let concurrent = RefCell::new(vec![]);
+
+        // Another worker looks for due schedules while this one is still refreshing the token
+        let refresh = |_: &str| {
+            concurrent.borrow_mut().extend(enqueue_scheduled(pool.clone(), &key, &|_| token()).unwrap());
+            token()
+        };
+
+        let enqueued = enqueue_scheduled(pool.clone(), &key, &refresh)?;
+        assert_eq!(enqueued.len(), 1);
+        assert!(concurrent.borrow().is_empty());
+
+        assert_eq!(db::backup_request::find_for_schedule(pool.get()?, Some(&key), schedule.id)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_saves_schedule_error_if_refresh_fails() -> Result<(), Error> {
+        let pool = new_db()?;
+        let key = test_key();
+        let mut schedule = new_schedule();
+        schedule.refresh_token = key.seal("my refresh token")?;
+        schedule.next_run_at = time::get_time() - time::Duration::minutes(1);
+        db::backup_schedule::create(pool.get()?, &schedule)?;
+
+        let enqueued = enqueue_scheduled(pool.clone(), &key, &|_| None)?;
+        assert!(enqueued.is_empty());
+
+        let saved = db::backup_schedule::find(pool.get()?, schedule.id)?.unwrap();
+        assert_eq!(saved.last_error.unwrap(), "could not refresh spotify token");
+        assert!(saved.next_run_at > time::get_time());
+
+        Ok(())
+    }
 }
@@ -2,6 +2,8 @@ use uuid::Uuid;
 use rspotify::spotify::oauth2::TokenInfo;
 use time::Timespec;
 use std::path::PathBuf;
+use crate::progress::Progress;
+use crate::options::BackupOptions;
 
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
@@ -13,18 +15,100 @@ pub struct BackupRequest {
     pub time_created: Timespec,
     pub file: Option<PathBuf>,
     pub last_error: Option<String>,
+    pub warning_count: u32,
+    pub progress: Option<Progress>,
+    pub options: BackupOptions,
+    pub schedule_id: Option<Uuid>,
+    // spotify user id, `None` for requests created before users were saved
+    pub user_id: Option<String>,
+    pub file_size: Option<i64>,
+    // `None` for scheduled backups, they are kept by `scheduled_backups_kept` instead
+    pub expires_at: Option<Timespec>,
+    // how many times a worker claimed the request, failed attempts are retried at `next_attempt_at`
+    pub attempts: u32,
+    pub next_attempt_at: Option<Timespec>,
+    // id of the http request that created it, to find the logs of both. `None` for scheduled backups
+    pub request_id: Option<String>,
 }
 
-//    Pending +--> Executed --> timeout ----> CompletedOk
-//            |
-//            +--> Error    --> timeout ----> CompletedError
-#[derive(Debug, serde::Serialize, PartialEq, Clone)]
+// A request as listed to operators in the admin section, without its token or options
+#[derive(Debug)]
+pub struct AdminBackupRequest {
+    pub id: Uuid,
+    pub status: RequestStatus,
+    pub user_id: Option<String>,
+    pub schedule_id: Option<Uuid>,
+    pub time_created: Timespec,
+    // when a worker last claimed the request and when it stopped running
+    pub started_at: Option<Timespec>,
+    pub finished_at: Option<Timespec>,
+    pub attempts: u32,
+    pub claimed_by: Option<String>,
+    pub last_error: Option<String>,
+    pub warning_count: u32,
+    pub file_size: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct User {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub time_created: Timespec,
+    pub last_login: Timespec,
+}
+
+// A recurring backup, a new `BackupRequest` is created every time `next_run_at` is reached
+#[derive(Debug)]
+pub struct BackupSchedule {
+    pub id: Uuid,
+    pub user_id: String,
+    // encrypted with the configured `TokenKey`
+    pub refresh_token: String,
+    pub frequency: Frequency,
+    pub options: BackupOptions,
+    pub time_created: Timespec,
+    pub next_run_at: Timespec,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Frequency {
+    Daily,
+    Weekly,
+}
+
+impl Frequency {
+    pub fn interval(&self) -> time::Duration {
+        match self {
+            Self::Daily => time::Duration::days(1),
+            Self::Weekly => time::Duration::weeks(1),
+        }
+    }
+}
+
+// Tokens sealed again by `rotate_keys`, tokens that can't be opened with any key are logged and left as they are
+#[derive(Debug, Default, PartialEq)]
+pub struct Rotation {
+    pub rotated: u32,
+    pub failed: u32,
+}
+
+//    Pending --> Running +--> Executed --> timeout ----> CompletedOk
+//                ^   |   |
+//                +---+   +--> Error    --> timeout ----> CompletedError
+//          lease expired
+//
+//    Pending or Running --> Cancelled, by the user
+#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq, Clone)]
 pub enum RequestStatus {
     Pending,
+    Running,
     Executed,
     Error,
     CompletedOk,
     CompletedError,
+    Cancelled,
 }
 
 impl RequestStatus {
@@ -41,49 +125,79 @@ impl RequestStatus {
             _ => false
         }
     }
+
+    pub fn cancellable(&self) -> bool {
+        match self {
+            Self::Pending | Self::Running => true,
+            _ => false
+        }
+    }
 }
 
 
 pub mod backup_request {
     use super::Connection;
-    use super::BackupRequest;
+    use super::{BackupRequest, Rotation};
     use rspotify::spotify::oauth2::TokenInfo;
     use failure::Error;
     use uuid::Uuid;
-    use rusqlite::types::{FromSql, FromSqlError, ValueRef, ToSqlOutput};
+    use rusqlite::types::{FromSql, FromSqlError, ValueRef, ToSqlOutput, Type};
     use rusqlite::{OptionalExtension, ToSql};
     use std::path::PathBuf;
     use rusqlite::params;
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
     use crate::server::db::RequestStatus;
+    use crate::server::crypto::Keyring;
+    use crate::progress::Progress;
+    use crate::options::BackupOptions;
+
+    // Columns read by `from_row`, in order
+    const COLUMNS: &str = "id, token, created_at, file, last_error, warning_count, progress, options, schedule_id, user_id, file_size, expires_at, attempts, next_attempt_at, request_id";
 
-    fn from_row(row: &rusqlite::Row) -> Result<BackupRequest, rusqlite::Error> {
+    // `keyring` decrypts the token, see `encode_token`
+    fn from_row(keyring: Option<&Keyring>, row: &rusqlite::Row) -> Result<BackupRequest, rusqlite::Error> {
         let id: SqlUuid = row.get(0)?;
-        let token: SqlTokenInfo = row.get(1)?;
+        let token: String = row.get(1)?;
+        let token = decode_token(keyring, &token)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(1, Type::Text, err.into()))?;
         let path: Option<SqlPathBuf> = row.get(3)?;
+        let progress: Option<SqlProgress> = row.get(6)?;
+        let options: SqlBackupOptions = row.get(7)?;
+        let schedule_id: Option<SqlUuid> = row.get(8)?;
 
         Ok(BackupRequest {
             id: id.0,
-            token: token.0,
+            token,
             time_created: row.get(2)?,
             file: path.map(|p| p.0),
-            last_error: row.get(4)?
+            last_error: row.get(4)?,
+            warning_count: row.get(5)?,
+            progress: progress.map(|p| p.0),
+            options: options.0,
+            schedule_id: schedule_id.map(|s| s.0),
+            user_id: row.get(9)?,
+            file_size: row.get(10)?,
+            expires_at: row.get(11)?,
+            attempts: row.get(12)?,
+            next_attempt_at: row.get(13)?,
+            request_id: row.get(14)?,
         })
     }
 
-    pub fn create(c: Connection, req: &BackupRequest) -> Result<Uuid, Error> {
-        let oauth_json = SqlTokenInfo(req.token.clone());
+    pub fn create(c: Connection, keyring: Option<&Keyring>, req: &BackupRequest) -> Result<Uuid, Error> {
+        let oauth_json = encode_token(keyring, &req.token)?;
 
-        c.execute("INSERT INTO backup_requests (id, token, status, created_at) VALUES (?1, ?2, ?3, ?4)",
-                 params![req.id.to_string(), &oauth_json, &RequestStatus::Pending, &time::get_time()])
+        c.execute("INSERT INTO backup_requests (id, token, status, created_at, options, schedule_id, user_id, expires_at, request_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                 params![req.id.to_string(), &oauth_json, &RequestStatus::Pending, &time::get_time(), SqlBackupOptions(req.options.clone()),
+                         req.schedule_id.map(|id| id.to_string()), req.user_id, req.expires_at, req.request_id])
             .map(|_| req.id)
             .map_err(Error::from)
     }
 
-    pub fn set_error(c: Connection, id: Uuid, error: &str) -> Result<(), Error> {
-        let count = c.execute("UPDATE backup_requests set last_error = ?, status = ? WHERE id = ?",
-                              params![error, RequestStatus::Error, id.to_string()])
+    // The `set_error`, `set_executed` and `set_retry` updates only apply while `claim_id` holds the request, a worker that
+    // lost its claim, eg. its lease expired, can't overwrite the result of the worker that claimed it again or a cancel
+    pub fn set_error(c: Connection, id: Uuid, claim_id: Uuid, error: &str) -> Result<(), Error> {
+        let count = c.execute("UPDATE backup_requests set last_error = ?, status = ?, finished_at = ? WHERE id = ? AND claim_id = ? AND status = ?",
+                              params![error, RequestStatus::Error, &time::get_time(), id.to_string(), claim_id.to_string(), RequestStatus::Running])
             .map_err(Error::from)?;
 
         if count > 0 {
@@ -101,7 +215,7 @@ pub mod backup_request {
         };
 
         let count = c.execute("UPDATE backup_requests set status = ?, file = NULL, token = ? WHERE id = ?",
-                              params![final_status, SqlTokenInfo(TokenInfo::default()), id.to_string()])
+                              params![final_status, cleared_token()?, id.to_string()])
             .map_err(Error::from)?;
 
         if count > 0 {
@@ -111,9 +225,10 @@ pub mod backup_request {
         }
     }
 
-    pub fn set_executed(c: Connection, id: Uuid, file: &PathBuf) -> Result<(), Error> {
-        let count = c.execute("UPDATE backup_requests set file = ?, status = ? WHERE id = ?",
-                              params![file.to_str(), RequestStatus::Executed, id.to_string()])
+    // a cancelled backup stays cancelled even if it finished meanwhile
+    pub fn set_executed(c: Connection, id: Uuid, claim_id: Uuid, file: &PathBuf, warning_count: u32, file_size: i64) -> Result<(), Error> {
+        let count = c.execute("UPDATE backup_requests set file = ?, warning_count = ?, file_size = ?, status = ?, finished_at = ? WHERE id = ? AND claim_id = ? AND status = ?",
+                              params![file.to_str(), warning_count, file_size, RequestStatus::Executed, &time::get_time(), id.to_string(), claim_id.to_string(), RequestStatus::Running])
             .map_err(Error::from)?;
 
         if count > 0 {
@@ -123,47 +238,100 @@ pub mod backup_request {
         }
     }
 
-    fn add_thread_id_function(c: &Connection, total_thread_count: u32) -> Result<(), Error> {
-        c.create_scalar_function("sb_thread_id", 1, true, move |ctx| {
-            assert_eq!(ctx.len(), 1, "called with unexpected number of arguments");
+    pub fn set_progress(c: Connection, id: Uuid, progress: &Progress) -> Result<(), Error> {
+        let count = c.execute("UPDATE backup_requests set progress = ? WHERE id = ? AND (status = ? OR status = ?)",
+                              params![SqlProgress(progress.clone()), id.to_string(), RequestStatus::Pending, RequestStatus::Running])
+            .map_err(Error::from)?;
+
+        if count > 0 {
+            Ok(())
+        } else {
+            failure::bail!("could not set progress on BackupRequest, updated 0 rows")
+        }
+    }
 
-            let data = ctx.get::<String>(0)?;
+    // Claims the oldest pending request due for an attempt, or a running one whose worker stopped renewing
+    // its lease, in a single update so concurrent workers, in any process, never claim the same request
+    pub fn claim_next(c: Connection, keyring: Option<&Keyring>, worker_id: &str, claim_id: Uuid, lease: time::Duration) -> Result<Option<BackupRequest>, Error> {
+        let now = time::get_time();
+
+        let count = c.execute(
+            "UPDATE backup_requests set status = ?1, claimed_by = ?2, claim_id = ?3, lease_expires_at = ?4, attempts = attempts + 1, started_at = ?6 \
+            WHERE id = (SELECT id FROM backup_requests \
+                        where (status = ?5 AND (next_attempt_at IS NULL OR next_attempt_at <= ?6)) \
+                           OR (status = ?1 AND lease_expires_at < ?6) \
+                        order by created_at asc limit 1)",
+            params![RequestStatus::Running, worker_id, claim_id.to_string(), &(now + lease), RequestStatus::Pending, &now])?;
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let mut stmt = c.prepare(&format!("SELECT {} FROM backup_requests where claim_id = ?", COLUMNS))?;
+        stmt.query_row(params![claim_id.to_string()], |row| from_row(keyring, row)).optional().map_err(Error::from)
+    }
+
+    // Returns false if the backup already finished. The worker running it, if any, stops at its next progress update
+    pub fn cancel(c: Connection, id: Uuid) -> Result<bool, Error> {
+        let count = c.execute("UPDATE backup_requests set status = ?, token = ?, finished_at = ?, claimed_by = NULL, claim_id = NULL, lease_expires_at = NULL \
+                               WHERE id = ? AND (status = ? OR status = ?)",
+                              params![RequestStatus::Cancelled, cleared_token()?, &time::get_time(), id.to_string(),
+                                      RequestStatus::Pending, RequestStatus::Running])?;
+
+        Ok(count > 0)
+    }
+
+    pub fn find_status(c: Connection, id: Uuid) -> Result<Option<RequestStatus>, Error> {
+        c.query_row("SELECT status FROM backup_requests where id = ?", params![id.to_string()], |row| row.get(0))
+            .optional()
+            .map_err(Error::from)
+    }
 
-            let mut hasher = DefaultHasher::new();
-            data.hash(&mut hasher);
-            let hash = hasher.finish();
+    // Puts a failed request back in the queue, it is claimed again once `next_attempt_at` is reached
+    pub fn set_retry(c: Connection, id: Uuid, claim_id: Uuid, next_attempt_at: time::Timespec) -> Result<(), Error> {
+        let count = c.execute("UPDATE backup_requests set status = ?, next_attempt_at = ?, progress = NULL, \
+                               claimed_by = NULL, claim_id = NULL, lease_expires_at = NULL WHERE id = ? AND claim_id = ? AND status = ?",
+                              params![RequestStatus::Pending, &next_attempt_at, id.to_string(), claim_id.to_string(), RequestStatus::Running])
+            .map_err(Error::from)?;
 
-            Ok((hash % total_thread_count as u64) as i64)
-        }).map_err(Error::from)
+        if count > 0 {
+            Ok(())
+        } else {
+            failure::bail!("could not retry BackupRequest, updated 0 rows")
+        }
     }
 
-    pub fn oldest_pending(c: Connection, thread_id: u32, total_thread_count: u32) -> Result<Option<BackupRequest>, Error> {
-        add_thread_id_function(&c, total_thread_count)?;
+    // Returns false if the claim was lost, eg. the lease expired and another worker claimed the request
+    pub fn renew_lease(c: Connection, id: Uuid, claim_id: Uuid, lease: time::Duration) -> Result<bool, Error> {
+        let count = c.execute("UPDATE backup_requests set lease_expires_at = ? WHERE id = ? AND claim_id = ? AND status = ?",
+                              params![&(time::get_time() + lease), id.to_string(), claim_id.to_string(), RequestStatus::Running])?;
+
+        Ok(count > 0)
+    }
 
-        let mut stmt = c.prepare(
-            "SELECT id, token, created_at, file, last_error \
-            FROM backup_requests \
-            where status = ? and sb_thread_id(id) = ? \
-            order by created_at asc limit 1"
-        )?;
+    // Puts a running request back in the queue without counting it as an attempt, eg. when the daemon stops mid-backup
+    pub fn release(c: Connection, id: Uuid, claim_id: Uuid) -> Result<bool, Error> {
+        let count = c.execute("UPDATE backup_requests set status = ?, progress = NULL, attempts = MAX(attempts - 1, 0), \
+                               claimed_by = NULL, claim_id = NULL, lease_expires_at = NULL WHERE id = ? AND claim_id = ? AND status = ?",
+                              params![RequestStatus::Pending, id.to_string(), claim_id.to_string(), RequestStatus::Running])?;
 
-        stmt.query_row(
-            params![&RequestStatus::Pending, thread_id],
-            from_row).optional().map_err(Error::from)
+        Ok(count > 0)
     }
 
-    pub fn find_executed(c: Connection) -> Result<Vec<BackupRequest>, Error> {
-        let since = time::now_utc() - time::Duration::hours(1);
+    pub fn find_executed(c: Connection, keyring: Option<&Keyring>, retention: time::Duration, limit: u32) -> Result<Vec<BackupRequest>, Error> {
+        let now = time::get_time();
+        let since = now - retention;
 
-        log::debug!("using since = {}", since.rfc3339());
+        log::debug!("using since = {}", time::at_utc(since).rfc3339());
 
-        let mut stmt = c.prepare(
-            "SELECT id, token, created_at, file, last_error FROM backup_requests \
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM backup_requests \
             where (status = ? OR status = ?) \
-            and created_at < ? \
-            order by created_at desc LIMIT 5")?;
+            and (expires_at < ? OR (expires_at IS NULL and created_at < ?)) \
+            and schedule_id IS NULL \
+            order by created_at desc LIMIT ?", COLUMNS))?;
 
-        let rows = stmt.query_map(params![RequestStatus::Error, RequestStatus::Executed, &since.to_timespec()],from_row)?;
+        let rows = stmt.query_map(params![RequestStatus::Error, RequestStatus::Executed, &now, &since, limit], |row| from_row(keyring, row))?;
 
         let mut result = vec![];
 
@@ -174,16 +342,72 @@ pub mod backup_request {
         Ok(result)
     }
 
-    pub fn find_with_status(c: Connection, id: Uuid) -> Result<Option<(BackupRequest, RequestStatus)>, Error> {
-        let mut stmt = c.prepare("SELECT id, token, created_at, file, last_error, status FROM backup_requests where id = ?")?;
+    // Scheduled backups are kept until there are more than `keep` newer runs of the same schedule
+    pub fn find_expired_scheduled(c: Connection, keyring: Option<&Keyring>, keep: u32, limit: u32) -> Result<Vec<BackupRequest>, Error> {
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM backup_requests r \
+            where (status = ? OR status = ?) \
+            and schedule_id IS NOT NULL \
+            and (SELECT count(*) FROM backup_requests n where n.schedule_id = r.schedule_id and n.created_at > r.created_at) >= ? \
+            order by created_at asc LIMIT ?", COLUMNS))?;
+
+        let rows = stmt.query_map(params![RequestStatus::Error, RequestStatus::Executed, keep, limit], |row| from_row(keyring, row))?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn find_for_schedule(c: Connection, keyring: Option<&Keyring>, schedule_id: Uuid) -> Result<Vec<(BackupRequest, RequestStatus)>, Error> {
+        let mut stmt = c.prepare(&format!("SELECT {}, status FROM backup_requests where schedule_id = ? order by created_at desc", COLUMNS))?;
+
+        let rows = stmt.query_map(params![schedule_id.to_string()], |row| {
+            let req = from_row(keyring, row)?;
+            let status: RequestStatus = row.get("status")?;
+            Ok((req, status))
+        })?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn find_for_user(c: Connection, keyring: Option<&Keyring>, user_id: &str) -> Result<Vec<(BackupRequest, RequestStatus)>, Error> {
+        let mut stmt = c.prepare(&format!("SELECT {}, status FROM backup_requests where user_id = ? order by created_at desc LIMIT 100", COLUMNS))?;
+
+        let rows = stmt.query_map(params![user_id], |row| {
+            let req = from_row(keyring, row)?;
+            let status: RequestStatus = row.get("status")?;
+            Ok((req, status))
+        })?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn find_with_status(c: Connection, keyring: Option<&Keyring>, id: Uuid) -> Result<Option<(BackupRequest, RequestStatus)>, Error> {
+        let mut stmt = c.prepare(&format!("SELECT {}, status FROM backup_requests where id = ?", COLUMNS))?;
         stmt.query_row(params![&id.to_string()], move |row| {
-            let req = from_row(row)?;
-            let status: RequestStatus = row.get(5)?;
+            let req = from_row(keyring, row)?;
+            let status: RequestStatus = row.get("status")?;
             Ok((req, status))
         }).optional().map_err(Error::from)
     }
 
-    struct SqlUuid(Uuid);
+    pub(super) struct SqlUuid(pub(super) Uuid);
 
     impl FromSql for SqlUuid {
         fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
@@ -194,18 +418,91 @@ pub mod backup_request {
         }
     }
 
-    struct SqlTokenInfo(TokenInfo);
+    // Tokens are encrypted with the keyring, if any. Plain text json is still read
+    // so existing rows keep working after a key is configured
+    pub(super) fn encode_token(keyring: Option<&Keyring>, token: &TokenInfo) -> Result<String, Error> {
+        let json = serde_json::to_string(token)?;
+
+        match keyring {
+            Some(keyring) => keyring.seal(&json),
+            None => Ok(json),
+        }
+    }
+
+    pub(super) fn decode_token(keyring: Option<&Keyring>, value: &str) -> Result<TokenInfo, Error> {
+        if value.starts_with('{') {
+            return serde_json::from_str(value).map_err(Error::from);
+        }
+
+        let keyring = keyring.ok_or_else(|| failure::format_err!("token is encrypted but no token_key is configured"))?;
+        serde_json::from_str(&keyring.open(value)?).map_err(Error::from)
+    }
+
+    // Replaces the token of finished backups, there is nothing left to encrypt
+    fn cleared_token() -> Result<String, Error> {
+        encode_token(None, &TokenInfo::default())
+    }
+
+    // Seals tokens again with the current key, returns how many were changed
+    pub fn rotate_tokens(c: Connection, keyring: &Keyring) -> Result<Rotation, Error> {
+        let mut stmt = c.prepare("SELECT id, token FROM backup_requests")?;
+
+        let rows = stmt.query_map(params![], |row| {
+            let id: String = row.get(0)?;
+            let token: String = row.get(1)?;
+            Ok((id, token))
+        })?.collect::<Result<Vec<_>, _>>()?;
+
+        let mut rotation = Rotation::default();
+
+        for (id, token) in rows {
+            if token.starts_with('{') || keyring.is_outdated(&token) {
+                match decode_token(Some(keyring), &token) {
+                    Ok(token) => {
+                        c.execute("UPDATE backup_requests set token = ? WHERE id = ?", params![encode_token(Some(keyring), &token)?, id])?;
+                        rotation.rotated += 1;
+                    },
+                    Err(err) => {
+                        log::warn!("Could not open token of backup request {}: {}", id, err);
+                        rotation.failed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(rotation)
+    }
+
+    struct SqlProgress(Progress);
+
+    impl FromSql for SqlProgress {
+        fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
+            value
+                .as_str()
+                .and_then(|s| serde_json::from_str::<Progress>(s).map_err(|_| FromSqlError::InvalidType))
+                .map(SqlProgress)
+        }
+    }
+
+    impl ToSql for SqlProgress {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            let s = serde_json::to_string(&self.0).map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))?;
+            Ok(ToSqlOutput::from(s))
+        }
+    }
+
+    pub(super) struct SqlBackupOptions(pub(super) BackupOptions);
 
-    impl FromSql for SqlTokenInfo {
+    impl FromSql for SqlBackupOptions {
         fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
             value
                 .as_str()
-                .and_then(|s| serde_json::from_str::<TokenInfo>(s).map_err(|_| FromSqlError::InvalidType))
-                .map(SqlTokenInfo)
+                .and_then(|s| serde_json::from_str::<BackupOptions>(s).map_err(|_| FromSqlError::InvalidType))
+                .map(SqlBackupOptions)
         }
     }
 
-    impl ToSql for SqlTokenInfo {
+    impl ToSql for SqlBackupOptions {
         fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
             let s = serde_json::to_string(&self.0).map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))?;
             Ok(ToSqlOutput::from(s))
@@ -233,10 +530,12 @@ pub mod backup_request {
         fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
             match value.as_str()? {
                 "Pending" => Ok(RequestStatus::Pending),
+                "Running" => Ok(RequestStatus::Running),
                 "Executed" => Ok(RequestStatus::Executed),
                 "Error" => Ok(RequestStatus::Error),
                 "CompletedOk" => Ok(RequestStatus::CompletedOk),
                 "CompletedError" => Ok(RequestStatus::CompletedError),
+                "Cancelled" => Ok(RequestStatus::Cancelled),
                 s => Err(FromSqlError::Other(failure::format_err!("Could not parse RequestStatus: {}", s).into()))
             }
         }
@@ -245,31 +544,48 @@ pub mod backup_request {
     #[cfg(test)]
     pub mod tests {
         use super::*;
-        use rusqlite::NO_PARAMS;
         use r2d2_sqlite::SqliteConnectionManager;
         use crate::server::db::Pool;
+        use crate::server::crypto::TokenKey;
 
         pub fn new_db() -> Result<Pool, Error> {
             let manager = SqliteConnectionManager::memory();
             let pool = Pool::new(manager)?;
-            let schema_str = std::fs::read_to_string("./schema.sql")?;
-            pool.get().unwrap().execute(&schema_str, NO_PARAMS)?;
+            crate::server::migrations::migrate(&mut pool.get()?)?;
             Ok(pool)
         }
 
-        fn new_req() -> BackupRequest {
+        pub fn new_req() -> BackupRequest {
             BackupRequest {
                 id: Uuid::new_v4(),
                 token: TokenInfo::default(),
                 time_created: time::get_time(),
                 file: None,
                 last_error: None,
+                warning_count: 0,
+                progress: None,
+                options: BackupOptions::default(),
+                schedule_id: None,
+                user_id: None,
+                file_size: None,
+                expires_at: None,
+                attempts: 0,
+                next_attempt_at: None,
+                request_id: None,
             }
         }
 
+        // Sets the request running as if a worker claimed it, returns the claim
+        pub fn claimed(pool: &Pool, id: Uuid) -> Result<Uuid, Error> {
+            let claim_id = Uuid::new_v4();
+            pool.get()?.execute("UPDATE backup_requests set status = ?, claim_id = ? where id = ?",
+                                params![RequestStatus::Running, claim_id.to_string(), id.to_string()])?;
+            Ok(claim_id)
+        }
+
         fn create_past(pool: &Pool) -> Result<BackupRequest, Error> {
             let req = new_req();
-            create(pool.get()?, &req)?;
+            create(pool.get()?, None, &req)?;
             set_old(pool.get()?, req.id, None)?;
             Ok(req)
         }
@@ -280,7 +596,15 @@ pub mod backup_request {
                     self.token.access_token == other.token.access_token && // good enough
                     self.time_created.sec == other.time_created.sec && // ignores nsec, rusqlite loses precision when saving to db
                     self.file == other.file &&
-                    self.last_error == other.last_error
+                    self.last_error == other.last_error &&
+                    self.warning_count == other.warning_count &&
+                    self.progress == other.progress &&
+                    self.options == other.options &&
+                    self.schedule_id == other.schedule_id &&
+                    self.user_id == other.user_id &&
+                    self.file_size == other.file_size &&
+                    self.expires_at.map(|t| t.sec) == other.expires_at.map(|t| t.sec) &&
+                    self.attempts == other.attempts
             }
         }
 
@@ -294,48 +618,137 @@ pub mod backup_request {
         fn test_add_pending_backup() -> Result<(), Error> {
             let pool = new_db()?;
             let req = new_req();
-            let res = create(pool.get()?, &req)?;
+            let res = create(pool.get()?, None, &req)?;
 
             assert_eq!(res, req.id);
             Ok(())
         }
 
         #[test]
-        fn test_pending_returns_latest_backup() -> Result<(), Error> {
+        fn test_claims_oldest_pending_backup() -> Result<(), Error> {
             let pool = new_db()?;
+            let old = new_req();
             let req = new_req();
 
-            create(pool.get()?, &req)?;
+            create(pool.get()?, None, &old)?;
+            set_old(pool.get()?, old.id, Some(time::Duration::minutes(5)))?;
+            create(pool.get()?, None, &req)?;
+
+            let claimed = claim_next(pool.get()?, None, "worker-1", Uuid::new_v4(), time::Duration::minutes(5))?;
+            assert_eq!(claimed.unwrap().id, old.id);
 
-            let pending = oldest_pending(pool.get()?, 0, 1)?;
+            let claimed = claim_next(pool.get()?, None, "worker-2", Uuid::new_v4(), time::Duration::minutes(5))?;
+            assert_eq!(claimed.unwrap().id, req.id);
 
-            assert_eq!(pending.unwrap().id, req.id);
+            let (_, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+            assert_eq!(status, RequestStatus::Running);
+
+            assert_eq!(claim_next(pool.get()?, None, "worker-3", Uuid::new_v4(), time::Duration::minutes(5))?, None);
             Ok(())
         }
 
         #[test]
-        fn test_gets_backups_for_specified_thread_only() -> Result<(), Error> {
+        fn test_reclaims_expired_leases() -> Result<(), Error> {
             let pool = new_db()?;
-            let mut req = new_req();
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+
+            let first_claim = Uuid::new_v4();
+            claim_next(pool.get()?, None, "worker-1", first_claim, time::Duration::seconds(-1))?.unwrap();
+
+            let second_claim = Uuid::new_v4();
+            let reclaimed = claim_next(pool.get()?, None, "worker-2", second_claim, time::Duration::minutes(5))?;
+            assert_eq!(reclaimed.unwrap().id, req.id);
+
+            assert!(!renew_lease(pool.get()?, req.id, first_claim, time::Duration::minutes(5))?);
+            assert!(renew_lease(pool.get()?, req.id, second_claim, time::Duration::minutes(5))?);
+            Ok(())
+        }
+
+        #[test]
+        fn test_does_not_reclaim_renewed_leases() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+
+            let claim = Uuid::new_v4();
+            claim_next(pool.get()?, None, "worker-1", claim, time::Duration::seconds(-1))?.unwrap();
+            assert!(renew_lease(pool.get()?, req.id, claim, time::Duration::minutes(5))?);
+
+            assert_eq!(claim_next(pool.get()?, None, "worker-2", Uuid::new_v4(), time::Duration::minutes(5))?, None);
+            Ok(())
+        }
+
+        #[test]
+        fn test_releases_claimed_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
 
-            let id_1 = Uuid::parse_str("35027614-7cc0-4d51-81f5-fbcc5eeae2e5")?; // has sb_thread_id = 1
-            let id_2 = Uuid::parse_str("d2c70a33-c1a2-4ee1-9061-f8aa80262466")?; // has sb_thread_id = 2
+            let claim = Uuid::new_v4();
+            claim_next(pool.get()?, None, "worker-1", claim, time::Duration::minutes(5))?.unwrap();
 
-            req.id = id_1;
-            create(pool.get()?, &req)?;
+            assert!(!release(pool.get()?, req.id, Uuid::new_v4())?);
+            assert!(release(pool.get()?, req.id, claim)?);
 
-            req.id = id_2;
-            create(pool.get()?, &req)?;
+            let claimed = claim_next(pool.get()?, None, "worker-2", Uuid::new_v4(), time::Duration::minutes(5))?.unwrap();
+            assert_eq!(claimed.id, req.id);
+            assert_eq!(claimed.attempts, 1);
+            Ok(())
+        }
 
-            let pending = oldest_pending(pool.get()?, 0, 3)?;
-            assert_eq!(pending, None);
+        #[test]
+        fn test_retries_after_next_attempt() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+
+            let claim = Uuid::new_v4();
+            let claimed = claim_next(pool.get()?, None, "worker-1", claim, time::Duration::minutes(5))?.unwrap();
+            assert_eq!(claimed.attempts, 1);
+
+            set_retry(pool.get()?, req.id, claim, time::get_time() + time::Duration::minutes(1))?;
+            assert_eq!(claim_next(pool.get()?, None, "worker-1", Uuid::new_v4(), time::Duration::minutes(5))?, None);
+
+            pool.get()?.execute("UPDATE backup_requests set next_attempt_at = ? where id = ?",
+                                params![&(time::get_time() - time::Duration::seconds(1)), req.id.to_string()])?;
+            let claimed = claim_next(pool.get()?, None, "worker-1", Uuid::new_v4(), time::Duration::minutes(5))?.unwrap();
+            assert_eq!(claimed.id, req.id);
+            assert_eq!(claimed.attempts, 1);
+            Ok(())
+        }
 
-            let pending = oldest_pending(pool.get()?, 1, 3)?;
-            assert_eq!(pending.unwrap().id, id_1);
+        #[test]
+        fn test_cancels_pending_and_running_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+            let pending = new_req();
+            let running = new_req();
+            create(pool.get()?, None, &running)?;
+            set_old(pool.get()?, running.id, Some(time::Duration::minutes(5)))?;
+            create(pool.get()?, None, &pending)?;
+            let claim = Uuid::new_v4();
+            claim_next(pool.get()?, None, "worker-1", claim, time::Duration::minutes(5))?.unwrap();
+
+            assert!(cancel(pool.get()?, pending.id)?);
+            assert!(cancel(pool.get()?, running.id)?);
+            assert_eq!(find_status(pool.get()?, running.id)?, Some(RequestStatus::Cancelled));
+            assert_eq!(claim_next(pool.get()?, None, "worker-1", Uuid::new_v4(), time::Duration::minutes(5))?, None);
+
+            assert!(set_executed(pool.get()?, running.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100).is_err());
+            assert_eq!(find_status(pool.get()?, running.id)?, Some(RequestStatus::Cancelled));
+            Ok(())
+        }
 
-            let pending = oldest_pending(pool.get()?, 2, 3)?;
-            assert_eq!(pending.unwrap().id, id_2);
+        #[test]
+        fn test_does_not_cancel_finished_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+            let claim = claimed(&pool, req.id)?;
+            set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
 
+            assert!(!cancel(pool.get()?, req.id)?);
+            assert_eq!(find_status(pool.get()?, req.id)?, Some(RequestStatus::Executed));
             Ok(())
         }
 
@@ -343,9 +756,10 @@ pub mod backup_request {
         fn test_finds_error_requests() -> Result<(), Error> {
             let pool = new_db()?;
             let req = create_past(&pool)?;
-            set_error(pool.get()?, req.id, "[test] something happened")?;
+            let claim = claimed(&pool, req.id)?;
+            set_error(pool.get()?, req.id, claim, "[test] something happened")?;
 
-            let all_executed = find_executed(pool.get()?)?;
+            let all_executed = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
             let executed = all_executed.first().unwrap();
 
             assert_eq!(executed.id, req.id);
@@ -356,35 +770,236 @@ pub mod backup_request {
         fn test_finds_finished_requests() -> Result<(), Error> {
             let pool = new_db()?;
             let req = create_past(&pool)?;
-            set_executed(pool.get()?, req.id, &PathBuf::from("/tmp/done.json"))?;
+            let claim = claimed(&pool, req.id)?;
+            set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
 
-            let all_executed = find_executed(pool.get()?)?;
+            let all_executed = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
             let executed = all_executed.first().unwrap();
 
             assert_eq!(executed.id, req.id);
             Ok(())
         }
 
+        #[test]
+        fn test_finds_requests_after_they_expire() -> Result<(), Error> {
+            let pool = new_db()?;
+
+            let mut kept = new_req();
+            kept.expires_at = Some(time::get_time() + time::Duration::hours(2));
+            create(pool.get()?, None, &kept)?;
+            set_old(pool.get()?, kept.id, None)?;
+            let claim = claimed(&pool, kept.id)?;
+            set_executed(pool.get()?, kept.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
+
+            let mut expired = new_req();
+            expired.expires_at = Some(time::get_time() - time::Duration::minutes(1));
+            create(pool.get()?, None, &expired)?;
+            let claim = claimed(&pool, expired.id)?;
+            set_executed(pool.get()?, expired.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
+
+            let all_executed = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
+
+            assert_eq!(all_executed.len(), 1);
+            assert_eq!(all_executed[0].id, expired.id);
+            Ok(())
+        }
+
+        #[test]
+        fn test_limits_expired_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+
+            for _ in 0..3 {
+                let req = create_past(&pool)?;
+                let claim = claimed(&pool, req.id)?;
+                set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
+            }
+
+            let expired = find_executed(pool.get()?, None, time::Duration::hours(1), 2)?;
+            assert_eq!(expired.len(), 2);
+
+            Ok(())
+        }
+
         #[test]
         fn test_does_not_finds_completed_requests() -> Result<(), Error> {
             let pool = new_db()?;
             let req = create_past(&pool)?;
             set_completed(pool.get()?, req.id, false)?;
 
-            let all_executed = find_executed(pool.get()?)?;
+            let all_executed = find_executed(pool.get()?, None, time::Duration::hours(1), 5)?;
 
             assert_eq!(all_executed.len(), 0);
             Ok(())
         }
 
+        #[test]
+        fn test_saves_warning_count() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+            let claim = claimed(&pool, req.id)?;
+            set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 3, 100)?;
+
+            let (found, status) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+
+            assert_eq!(found.warning_count, 3);
+            assert_eq!(status, RequestStatus::Executed);
+            Ok(())
+        }
+
+        #[test]
+        fn test_saves_progress() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+
+            set_progress(pool.get()?, req.id, &Progress::new("albums", 50, 120))?;
+
+            let (found, _) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+
+            assert_eq!(found.progress, Some(Progress::new("albums", 50, 120)));
+            Ok(())
+        }
+
+        #[test]
+        fn test_saves_backup_options() -> Result<(), Error> {
+            use crate::options::Collection;
+
+            let pool = new_db()?;
+            let mut req = new_req();
+            req.options = BackupOptions::new(vec![Collection::LikedSongs]);
+            create(pool.get()?, None, &req)?;
+
+            let (found, _) = find_with_status(pool.get()?, None, req.id)?.unwrap();
+
+            assert_eq!(found.options.collections, vec![Collection::LikedSongs]);
+            Ok(())
+        }
+
+        #[test]
+        fn test_does_not_expire_scheduled_requests_after_an_hour() -> Result<(), Error> {
+            let pool = new_db()?;
+            let mut req = new_req();
+            req.schedule_id = Some(Uuid::new_v4());
+            create(pool.get()?, None, &req)?;
+            set_old(pool.get()?, req.id, None)?;
+            let claim = claimed(&pool, req.id)?;
+            set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
+
+            assert!(find_executed(pool.get()?, None, time::Duration::hours(1), 5)?.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn test_keeps_latest_scheduled_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+            let schedule_id = Uuid::new_v4();
+            let mut ids = vec![];
+
+            for age in 0..3 {
+                let mut req = new_req();
+                req.schedule_id = Some(schedule_id);
+                create(pool.get()?, None, &req)?;
+                set_old(pool.get()?, req.id, Some(time::Duration::days(3 - age)))?;
+                let claim = claimed(&pool, req.id)?;
+                set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
+                ids.push(req.id);
+            }
+
+            let expired = find_expired_scheduled(pool.get()?, None, 2, 5)?;
+            assert_eq!(expired.len(), 1);
+            assert_eq!(expired[0].id, ids[0]);
+
+            let history = find_for_schedule(pool.get()?, None, schedule_id)?;
+            let history_ids: Vec<Uuid> = history.iter().map(|(r, _)| r.id).collect();
+            assert_eq!(history_ids, vec![ids[2], ids[1], ids[0]]);
+            Ok(())
+        }
+
+        #[test]
+        fn test_encodes_tokens_with_keyring() -> Result<(), Error> {
+            let keyring = Keyring::new("k1", TokenKey::from_base64(&base64::encode(&[1u8; 32]))?);
+            let token = TokenInfo { access_token: "my access token".into(), ..TokenInfo::default() };
+
+            let plain = encode_token(None, &token)?;
+            let sealed = encode_token(Some(&keyring), &token)?;
+
+            assert!(plain.contains("my access token"));
+            assert!(!sealed.contains("my access token"));
+            assert_eq!(decode_token(None, &plain)?.access_token, "my access token");
+            assert_eq!(decode_token(Some(&keyring), &plain)?.access_token, "my access token");
+            assert_eq!(decode_token(Some(&keyring), &sealed)?.access_token, "my access token");
+            assert!(decode_token(None, &sealed).is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn test_rotates_tokens() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            create(pool.get()?, None, &req)?;
+
+            // Sealed with a key that is no longer configured, it can't be rotated but the others are
+            let lost = new_req();
+            let lost_keyring = Keyring::new("k0", TokenKey::from_base64(&base64::encode(&[2u8; 32]))?);
+            create(pool.get()?, Some(&lost_keyring), &lost)?;
+
+            let keyring = Keyring::new("k1", TokenKey::from_base64(&base64::encode(&[1u8; 32]))?);
+
+            assert_eq!(rotate_tokens(pool.get()?, &keyring)?, Rotation { rotated: 1, failed: 1 });
+            assert_eq!(rotate_tokens(pool.get()?, &keyring)?, Rotation { rotated: 0, failed: 1 });
+
+            let saved: String = pool.get()?.query_row("SELECT token FROM backup_requests where id = ?", params![req.id.to_string()], |row| row.get(0))?;
+            assert!(saved.starts_with("v1:k1:"));
+            assert_eq!(decode_token(Some(&keyring), &saved)?.access_token, req.token.access_token);
+            Ok(())
+        }
+
+        #[test]
+        fn test_seals_tokens_with_keyring() -> Result<(), Error> {
+            let pool = new_db()?;
+            let keyring = Keyring::new("k1", TokenKey::from_base64(&base64::encode(&[1u8; 32]))?);
+            let mut req = new_req();
+            req.token.access_token = "my access token".into();
+            create(pool.get()?, Some(&keyring), &req)?;
+
+            let saved: String = pool.get()?.query_row("SELECT token FROM backup_requests where id = ?", params![req.id.to_string()], |row| row.get(0))?;
+            assert!(saved.starts_with("v1:k1:"));
+            assert!(!saved.contains("my access token"));
+
+            let (found, _) = find_with_status(pool.get()?, Some(&keyring), req.id)?.unwrap();
+            assert_eq!(found, req);
+            assert!(find_with_status(pool.get()?, None, req.id).is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn test_find_for_user() -> Result<(), Error> {
+            let pool = new_db()?;
+            let mut req = new_req();
+            req.user_id = Some("myuser".into());
+            create(pool.get()?, None, &req)?;
+            create(pool.get()?, None, &new_req())?;
+            let claim = claimed(&pool, req.id)?;
+            set_executed(pool.get()?, req.id, claim, &PathBuf::from("/tmp/done.json"), 0, 2048)?;
+
+            let found = find_for_user(pool.get()?, None, "myuser")?;
+
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].0.id, req.id);
+            assert_eq!(found[0].0.file_size, Some(2048));
+            assert_eq!(found[0].1, RequestStatus::Executed);
+            Ok(())
+        }
+
         #[test]
         fn test_find() -> Result<(), Error> {
             let pool = new_db()?;
             let req = new_req();
 
-            create(pool.get()?, &req)?;
+            create(pool.get()?, None, &req)?;
 
-            let found = find_with_status(pool.get()?, req.id)?.unwrap();
+            let found = find_with_status(pool.get()?, None, req.id)?.unwrap();
 
             assert_eq!(found.0, req);
             assert_eq!(found.1, RequestStatus::Pending);
@@ -393,3 +1008,527 @@ pub mod backup_request {
     }
 }
 
+
+pub mod backup_schedule {
+    use super::{Connection, BackupSchedule, Frequency, Rotation};
+    use super::backup_request::{SqlUuid, SqlBackupOptions};
+    use crate::server::crypto::Keyring;
+    use failure::Error;
+    use uuid::Uuid;
+    use time::Timespec;
+    use rusqlite::types::{FromSql, FromSqlError, ValueRef, ToSqlOutput};
+    use rusqlite::{OptionalExtension, ToSql};
+    use rusqlite::params;
+
+    const COLUMNS: &str = "id, user_id, refresh_token, frequency, options, created_at, next_run_at, last_error";
+
+    fn from_row(row: &rusqlite::Row) -> Result<BackupSchedule, rusqlite::Error> {
+        let id: SqlUuid = row.get(0)?;
+        let options: SqlBackupOptions = row.get(4)?;
+
+        Ok(BackupSchedule {
+            id: id.0,
+            user_id: row.get(1)?,
+            refresh_token: row.get(2)?,
+            frequency: row.get(3)?,
+            options: options.0,
+            time_created: row.get(5)?,
+            next_run_at: row.get(6)?,
+            last_error: row.get(7)?,
+        })
+    }
+
+    pub fn create(c: Connection, schedule: &BackupSchedule) -> Result<Uuid, Error> {
+        c.execute("INSERT INTO backup_schedules (id, user_id, refresh_token, frequency, options, created_at, next_run_at) \
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                  params![schedule.id.to_string(), schedule.user_id, schedule.refresh_token, schedule.frequency,
+                          SqlBackupOptions(schedule.options.clone()), &schedule.time_created, &schedule.next_run_at])
+            .map(|_| schedule.id)
+            .map_err(Error::from)
+    }
+
+    pub fn find(c: Connection, id: Uuid) -> Result<Option<BackupSchedule>, Error> {
+        let mut stmt = c.prepare(&format!("SELECT {} FROM backup_schedules where id = ?", COLUMNS))?;
+        stmt.query_row(params![id.to_string()], from_row).optional().map_err(Error::from)
+    }
+
+    pub fn find_for_user(c: Connection, user_id: &str) -> Result<Vec<BackupSchedule>, Error> {
+        let mut stmt = c.prepare(&format!("SELECT {} FROM backup_schedules where user_id = ? order by created_at asc", COLUMNS))?;
+
+        let rows = stmt.query_map(params![user_id], from_row)?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn find_due(c: Connection, now: Timespec) -> Result<Vec<BackupSchedule>, Error> {
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM backup_schedules where next_run_at <= ? order by next_run_at asc LIMIT 5", COLUMNS))?;
+
+        let rows = stmt.query_map(params![&now], from_row)?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    // Claims the run that was due at `due_at`, several daemons can find the same due schedule but only
+    // the one that moves `next_run_at` gets true and enqueues it
+    pub fn set_next_run(c: Connection, id: Uuid, due_at: Timespec, next_run_at: Timespec) -> Result<bool, Error> {
+        let count = c.execute("UPDATE backup_schedules set next_run_at = ? WHERE id = ? AND next_run_at = ?",
+                              params![&next_run_at, id.to_string(), &due_at])?;
+
+        Ok(count == 1)
+    }
+
+    pub fn set_last_error(c: Connection, id: Uuid, last_error: Option<&str>) -> Result<(), Error> {
+        let count = c.execute("UPDATE backup_schedules set last_error = ? WHERE id = ?",
+                              params![last_error, id.to_string()])?;
+
+        if count > 0 {
+            Ok(())
+        } else {
+            failure::bail!("could not set last error on BackupSchedule, updated 0 rows")
+        }
+    }
+
+    pub fn set_refresh_token(c: Connection, id: Uuid, refresh_token: &str) -> Result<(), Error> {
+        let count = c.execute("UPDATE backup_schedules set refresh_token = ? WHERE id = ?",
+                              params![refresh_token, id.to_string()])?;
+
+        if count > 0 {
+            Ok(())
+        } else {
+            failure::bail!("could not set refresh token on BackupSchedule, updated 0 rows")
+        }
+    }
+
+    // Seals refresh tokens again with the current key
+    pub fn rotate_refresh_tokens(c: Connection, keyring: &Keyring) -> Result<Rotation, Error> {
+        let mut stmt = c.prepare("SELECT id, refresh_token FROM backup_schedules")?;
+
+        let rows = stmt.query_map(params![], |row| {
+            let id: String = row.get(0)?;
+            let refresh_token: String = row.get(1)?;
+            Ok((id, refresh_token))
+        })?.collect::<Result<Vec<_>, _>>()?;
+
+        let mut rotation = Rotation::default();
+
+        for (id, refresh_token) in rows {
+            if keyring.is_outdated(&refresh_token) {
+                match keyring.open(&refresh_token) {
+                    Ok(refresh_token) => {
+                        c.execute("UPDATE backup_schedules set refresh_token = ? WHERE id = ?", params![keyring.seal(&refresh_token)?, id])?;
+                        rotation.rotated += 1;
+                    },
+                    Err(err) => {
+                        log::warn!("Could not open refresh token of backup schedule {}: {}", id, err);
+                        rotation.failed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(rotation)
+    }
+
+    // Past runs are kept and then expire like any other backup request
+    pub fn delete(c: Connection, id: Uuid) -> Result<(), Error> {
+        c.execute("UPDATE backup_requests set schedule_id = NULL WHERE schedule_id = ?", params![id.to_string()])?;
+        c.execute("DELETE FROM backup_schedules WHERE id = ?", params![id.to_string()])?;
+        Ok(())
+    }
+
+    impl ToSql for Frequency {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            Ok(ToSqlOutput::from(format!("{:?}", self)))
+        }
+    }
+
+    impl FromSql for Frequency {
+        fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
+            match value.as_str()? {
+                "Daily" => Ok(Frequency::Daily),
+                "Weekly" => Ok(Frequency::Weekly),
+                s => Err(FromSqlError::Other(failure::format_err!("Could not parse Frequency: {}", s).into()))
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub mod tests {
+        use super::*;
+        use crate::server::db::backup_request::tests::new_db;
+        use crate::options::BackupOptions;
+
+        pub fn new_schedule() -> BackupSchedule {
+            BackupSchedule {
+                id: Uuid::new_v4(),
+                user_id: "myuser".into(),
+                refresh_token: "encrypted refresh token".into(),
+                frequency: Frequency::Daily,
+                options: BackupOptions::default(),
+                time_created: time::get_time(),
+                next_run_at: time::get_time() + time::Duration::days(1),
+                last_error: None,
+            }
+        }
+
+        #[test]
+        fn test_create_and_find() -> Result<(), Error> {
+            let pool = new_db()?;
+            let schedule = new_schedule();
+            create(pool.get()?, &schedule)?;
+
+            let found = find(pool.get()?, schedule.id)?.unwrap();
+
+            assert_eq!(found.user_id, "myuser");
+            assert_eq!(found.frequency, Frequency::Daily);
+            assert_eq!(found.refresh_token, schedule.refresh_token);
+            assert_eq!(found.next_run_at.sec, schedule.next_run_at.sec);
+            Ok(())
+        }
+
+        #[test]
+        fn test_find_due() -> Result<(), Error> {
+            let pool = new_db()?;
+            let schedule = new_schedule();
+            create(pool.get()?, &schedule)?;
+
+            assert!(find_due(pool.get()?, time::get_time())?.is_empty());
+
+            let due = find_due(pool.get()?, time::get_time() + time::Duration::days(2))?;
+            assert_eq!(due.first().unwrap().id, schedule.id);
+
+            assert!(set_next_run(pool.get()?, schedule.id, due[0].next_run_at, time::get_time() + time::Duration::days(3))?);
+            assert!(find_due(pool.get()?, time::get_time() + time::Duration::days(2))?.is_empty());
+            Ok(())
+        }
+
+        #[test]
+        fn test_set_next_run_only_claims_once() -> Result<(), Error> {
+            let pool = new_db()?;
+            let schedule = new_schedule();
+            create(pool.get()?, &schedule)?;
+
+            let due_at = find(pool.get()?, schedule.id)?.unwrap().next_run_at;
+            let next_run_at = due_at + time::Duration::days(1);
+
+            assert!(set_next_run(pool.get()?, schedule.id, due_at, next_run_at)?);
+            assert!(!set_next_run(pool.get()?, schedule.id, due_at, next_run_at)?);
+
+            assert_eq!(find(pool.get()?, schedule.id)?.unwrap().next_run_at, next_run_at);
+            Ok(())
+        }
+
+        #[test]
+        fn test_delete() -> Result<(), Error> {
+            let pool = new_db()?;
+            let schedule = new_schedule();
+            create(pool.get()?, &schedule)?;
+
+            delete(pool.get()?, schedule.id)?;
+
+            assert!(find(pool.get()?, schedule.id)?.is_none());
+            Ok(())
+        }
+    }
+}
+
+pub mod user {
+    use super::{Connection, User};
+    use failure::Error;
+    use rusqlite::OptionalExtension;
+    use rusqlite::params;
+
+    fn from_row(row: &rusqlite::Row) -> Result<User, rusqlite::Error> {
+        Ok(User {
+            id: row.get(0)?,
+            display_name: row.get(1)?,
+            time_created: row.get(2)?,
+            last_login: row.get(3)?,
+        })
+    }
+
+    // Creates the user the first time they login, afterwards only updates the login time and name
+    pub fn save_login(c: Connection, user: &User) -> Result<(), Error> {
+        c.execute("INSERT INTO users (id, display_name, created_at, last_login_at) VALUES (?1, ?2, ?3, ?4) \
+                   ON CONFLICT(id) DO UPDATE SET display_name = ?2, last_login_at = ?4",
+                  params![user.id, user.display_name, &user.time_created, &user.last_login])?;
+        Ok(())
+    }
+
+    pub fn find(c: Connection, id: &str) -> Result<Option<User>, Error> {
+        let mut stmt = c.prepare("SELECT id, display_name, created_at, last_login_at FROM users where id = ?")?;
+        stmt.query_row(params![id], from_row).optional().map_err(Error::from)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::server::db::backup_request::tests::new_db;
+
+        #[test]
+        fn test_save_login() -> Result<(), Error> {
+            let pool = new_db()?;
+            let first_login = time::get_time() - time::Duration::days(1);
+
+            let mut user = User {
+                id: "myuser".into(),
+                display_name: Some("My User".into()),
+                time_created: first_login,
+                last_login: first_login,
+            };
+
+            save_login(pool.get()?, &user)?;
+
+            user.display_name = Some("Renamed".into());
+            user.time_created = time::get_time();
+            user.last_login = time::get_time();
+            save_login(pool.get()?, &user)?;
+
+            let found = find(pool.get()?, "myuser")?.unwrap();
+
+            assert_eq!(found.display_name, Some("Renamed".to_owned()));
+            assert_eq!(found.time_created.sec, first_login.sec);
+            assert_eq!(found.last_login.sec, user.last_login.sec);
+            Ok(())
+        }
+    }
+}
+
+// Queries for operators, see `server::admin`
+pub mod admin {
+    use super::{Connection, AdminBackupRequest, RequestStatus};
+    use super::backup_request::SqlUuid;
+    use failure::Error;
+    use uuid::Uuid;
+    use rusqlite::{OptionalExtension, params};
+
+    const COLUMNS: &str = "id, status, user_id, schedule_id, created_at, started_at, finished_at, attempts, claimed_by, last_error, warning_count, file_size";
+
+    fn from_row(row: &rusqlite::Row) -> Result<AdminBackupRequest, rusqlite::Error> {
+        let id: SqlUuid = row.get(0)?;
+        let schedule_id: Option<SqlUuid> = row.get(3)?;
+
+        Ok(AdminBackupRequest {
+            id: id.0,
+            status: row.get(1)?,
+            user_id: row.get(2)?,
+            schedule_id: schedule_id.map(|s| s.0),
+            time_created: row.get(4)?,
+            started_at: row.get(5)?,
+            finished_at: row.get(6)?,
+            attempts: row.get(7)?,
+            claimed_by: row.get(8)?,
+            last_error: row.get(9)?,
+            warning_count: row.get(10)?,
+            file_size: row.get(11)?,
+        })
+    }
+
+    // Newest first, every status if `status` is `None`
+    pub fn find_requests(c: Connection, status: Option<RequestStatus>, limit: u32) -> Result<Vec<AdminBackupRequest>, Error> {
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM backup_requests where (?1 IS NULL OR status = ?1) order by created_at desc LIMIT ?2", COLUMNS))?;
+
+        let rows = stmt.query_map(params![status, limit], from_row)?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn find_request(c: Connection, id: Uuid) -> Result<Option<AdminBackupRequest>, Error> {
+        let mut stmt = c.prepare(&format!("SELECT {} FROM backup_requests where id = ?", COLUMNS))?;
+        stmt.query_row(params![id.to_string()], from_row).optional().map_err(Error::from)
+    }
+
+    pub fn count_by_status(c: Connection) -> Result<Vec<(RequestStatus, u32)>, Error> {
+        let mut stmt = c.prepare("SELECT status, count(*) FROM backup_requests group by status order by status")?;
+
+        let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(row?);
+        }
+
+        Ok(result)
+    }
+
+    // Puts a failed request back in the queue right away, with all its attempts. The request stays
+    // available for as long as it would have been if it had not failed
+    pub fn requeue(c: Connection, id: Uuid) -> Result<bool, Error> {
+        let found: Option<(time::Timespec, Option<time::Timespec>)> = c.query_row(
+            "SELECT created_at, expires_at FROM backup_requests where id = ? AND status = ?",
+            params![id.to_string(), RequestStatus::Error],
+            |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+
+        let (created_at, expires_at) = match found {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+
+        let now = time::get_time();
+        let expires_at = expires_at.map(|e| now + (e - created_at));
+
+        let count = c.execute("UPDATE backup_requests set status = ?, last_error = NULL, progress = NULL, next_attempt_at = NULL, attempts = 0, \
+                               expires_at = ?, started_at = NULL, finished_at = NULL WHERE id = ? AND status = ?",
+                              params![RequestStatus::Pending, expires_at, id.to_string(), RequestStatus::Error])?;
+
+        Ok(count > 0)
+    }
+
+    // Deletes the request, its backup file must be deleted from the store separately. Running
+    // requests must be cancelled first
+    pub fn purge(c: Connection, id: Uuid) -> Result<bool, Error> {
+        let count = c.execute("DELETE FROM backup_requests WHERE id = ? AND status != ?",
+                              params![id.to_string(), RequestStatus::Running])?;
+
+        Ok(count > 0)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::server::db::backup_request::{self, tests::{claimed, new_db, new_req, set_old}};
+        use std::path::PathBuf;
+
+        #[test]
+        fn test_finds_requests_by_status() -> Result<(), Error> {
+            let pool = new_db()?;
+            let failed = new_req();
+            let pending = new_req();
+            backup_request::create(pool.get()?, None, &failed)?;
+            backup_request::create(pool.get()?, None, &pending)?;
+            let claim = claimed(&pool, failed.id)?;
+            backup_request::set_error(pool.get()?, failed.id, claim, "[test] failed")?;
+
+            let found = find_requests(pool.get()?, Some(RequestStatus::Error), 10)?;
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].id, failed.id);
+            assert_eq!(found[0].last_error, Some("[test] failed".to_owned()));
+            assert!(found[0].finished_at.is_some());
+
+            assert_eq!(find_requests(pool.get()?, None, 10)?.len(), 2);
+            assert_eq!(count_by_status(pool.get()?)?, vec![(RequestStatus::Error, 1), (RequestStatus::Pending, 1)]);
+            Ok(())
+        }
+
+        #[test]
+        fn test_requeues_failed_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+            let req = new_req();
+            backup_request::create(pool.get()?, None, &req)?;
+            set_old(pool.get()?, req.id, None)?;
+
+            assert!(!requeue(pool.get()?, req.id)?);
+
+            let claim = Uuid::new_v4();
+            backup_request::claim_next(pool.get()?, None, "worker-1", claim, time::Duration::minutes(5))?.unwrap();
+            backup_request::set_error(pool.get()?, req.id, claim, "[test] failed")?;
+            assert!(requeue(pool.get()?, req.id)?);
+
+            let found = find_request(pool.get()?, req.id)?.unwrap();
+            assert_eq!(found.status, RequestStatus::Pending);
+            assert!(found.last_error.is_none());
+            assert!(found.started_at.is_none());
+
+            let claimed = backup_request::claim_next(pool.get()?, None, "worker-1", Uuid::new_v4(), time::Duration::minutes(5))?.unwrap();
+            assert_eq!(claimed.id, req.id);
+            assert_eq!(claimed.attempts, 1);
+            Ok(())
+        }
+
+        #[test]
+        fn test_does_not_purge_running_requests() -> Result<(), Error> {
+            let pool = new_db()?;
+            let running = new_req();
+            let executed = new_req();
+            backup_request::create(pool.get()?, None, &running)?;
+            set_old(pool.get()?, running.id, Some(time::Duration::minutes(5)))?;
+            backup_request::create(pool.get()?, None, &executed)?;
+            backup_request::claim_next(pool.get()?, None, "worker-1", Uuid::new_v4(), time::Duration::minutes(5))?.unwrap();
+            let claim = claimed(&pool, executed.id)?;
+            backup_request::set_executed(pool.get()?, executed.id, claim, &PathBuf::from("/tmp/done.json"), 0, 100)?;
+
+            assert!(!purge(pool.get()?, running.id)?);
+            assert!(purge(pool.get()?, executed.id)?);
+            assert!(find_request(pool.get()?, executed.id)?.is_none());
+            assert!(find_request(pool.get()?, running.id)?.is_some());
+            Ok(())
+        }
+    }
+}
+
+// Written by every daemon worker while it runs, so the server can tell if any worker is alive
+pub mod heartbeat {
+    use super::Connection;
+    use failure::Error;
+    use time::Timespec;
+    use rusqlite::params;
+
+    pub fn beat(c: Connection, worker_id: &str, at: Timespec) -> Result<(), Error> {
+        c.execute("INSERT INTO worker_heartbeats (worker_id, last_seen_at) VALUES (?1, ?2) \
+                   ON CONFLICT(worker_id) DO UPDATE SET last_seen_at = ?2",
+                  params![worker_id, &at])?;
+        Ok(())
+    }
+
+    // Called when a worker stops cleanly, workers that crashed are just not seen anymore
+    pub fn delete(c: Connection, worker_id: &str) -> Result<(), Error> {
+        c.execute("DELETE FROM worker_heartbeats WHERE worker_id = ?", params![worker_id])?;
+        Ok(())
+    }
+
+    // How many workers were seen after `since`, and when a worker was last seen
+    pub fn find_alive(c: Connection, since: Timespec) -> Result<(u32, Option<Timespec>), Error> {
+        let alive: u32 = c.query_row("SELECT count(*) FROM worker_heartbeats where last_seen_at > ?",
+                                     params![&since], |row| row.get(0))?;
+        let last_seen: Option<Timespec> = c.query_row("SELECT max(last_seen_at) FROM worker_heartbeats",
+                                                      params![], |row| row.get(0))?;
+        Ok((alive, last_seen))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::server::db::backup_request::tests::new_db;
+
+        #[test]
+        fn test_finds_alive_workers() -> Result<(), Error> {
+            let pool = new_db()?;
+            let now = time::get_time();
+
+            assert_eq!(find_alive(pool.get()?, now - time::Duration::minutes(5))?, (0, None));
+
+            beat(pool.get()?, "worker-1", now - time::Duration::minutes(10))?;
+            beat(pool.get()?, "worker-2", now - time::Duration::minutes(10))?;
+            beat(pool.get()?, "worker-2", now)?;
+
+            let (alive, last_seen) = find_alive(pool.get()?, now - time::Duration::minutes(5))?;
+            assert_eq!(alive, 1);
+            assert_eq!(last_seen.unwrap().sec, now.sec);
+
+            delete(pool.get()?, "worker-2")?;
+            assert_eq!(find_alive(pool.get()?, now - time::Duration::minutes(5))?.0, 0);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use actix_web::{HttpResponse, web};
+use serde::Serialize;
+use std::sync::Arc;
+
+use super::app::{self, DefaultRenderer};
+use super::db::{self, Pool};
+use super::store::BackupStore;
+
+// Every template rendered by the server
+const TEMPLATES: &[&str] = &["index.html", "backup.html", "history.html", "schedule.html", "admin.html", "error.html"];
+
+// Workers are reported alive if they wrote a heartbeat within this long
+#[derive(Clone, Copy)]
+pub struct HeartbeatWindow(pub time::Duration);
+
+#[derive(Serialize)]
+pub struct Workers {
+    pub alive: u32,
+    pub last_seen_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    // "ok" or why the check failed
+    pub database: String,
+    pub templates: String,
+    pub storage: String,
+    // not part of `ready`, the server works without a daemon but backups are not made
+    pub workers: Option<Workers>,
+}
+
+fn check(result: &Result<(), failure::Error>) -> String {
+    match result {
+        Ok(()) => "ok".to_owned(),
+        Err(err) => format!("{}", err),
+    }
+}
+
+// Liveness, the server is answering requests
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+pub async fn readyz(pool: web::Data<Pool>, renderer: web::Data<DefaultRenderer>, store: web::Data<Arc<dyn BackupStore>>,
+                    window: web::Data<HeartbeatWindow>) -> HttpResponse {
+    let p = pool.get_ref().clone();
+    let s = store.get_ref().clone();
+    let since = time::get_time() - window.0;
+
+    let checked = web::block(move || -> Result<_, failure::Error> {
+        let database = p.get().map_err(failure::Error::from)
+            .and_then(|c| c.query_row("SELECT 1", rusqlite::params![], |_| Ok(())).map_err(failure::Error::from));
+
+        let workers = if database.is_ok() {
+            p.get().ok().and_then(|c| db::heartbeat::find_alive(c, since).ok())
+        } else {
+            None
+        };
+
+        Ok((database, s.check_writable(), workers))
+    }).await;
+
+    let (database, storage, workers) = match checked {
+        Ok(checked) => checked,
+        Err(err) => (Err(failure::format_err!("{}", err)), Err(failure::format_err!("{}", err)), None),
+    };
+
+    let missing: Vec<&str> = TEMPLATES.iter().cloned().filter(|t| !renderer.has_template(t)).collect();
+    let templates = if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(failure::format_err!("missing templates: {}", missing.join(", ")))
+    };
+
+    let resp = Readiness {
+        ready: database.is_ok() && templates.is_ok() && storage.is_ok(),
+        database: check(&database),
+        templates: check(&templates),
+        storage: check(&storage),
+        workers: workers.map(|(alive, last_seen_at)| Workers { alive, last_seen_at: last_seen_at.map(app::format_time) }),
+    };
+
+    if resp.ready {
+        HttpResponse::Ok().json(resp)
+    } else {
+        log::warn!("Not ready, database: {}, templates: {}, storage: {}", resp.database, resp.templates, resp.storage);
+        HttpResponse::ServiceUnavailable().json(resp)
+    }
+}
@@ -0,0 +1,220 @@
+use failure::Error;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use super::db::Pool;
+
+// Schema changes, applied in order and never edited once released. Add a new one for every change
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    sql: &'static str,
+    // a table and column that only exist after this migration, to find the version
+    // of databases created before `schema_version` existed
+    marker: (&'static str, &'static str),
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "baseline", sql: include_str!("../../migrations/0001_baseline.sql"), marker: ("backup_requests", "id") },
+    Migration { version: 2, name: "warning_count", sql: include_str!("../../migrations/0002_warning_count.sql"), marker: ("backup_requests", "warning_count") },
+    Migration { version: 3, name: "progress", sql: include_str!("../../migrations/0003_progress.sql"), marker: ("backup_requests", "progress") },
+    Migration { version: 4, name: "options", sql: include_str!("../../migrations/0004_options.sql"), marker: ("backup_requests", "options") },
+    Migration { version: 5, name: "backup_schedules", sql: include_str!("../../migrations/0005_backup_schedules.sql"), marker: ("backup_requests", "schedule_id") },
+    Migration { version: 6, name: "users", sql: include_str!("../../migrations/0006_users.sql"), marker: ("backup_requests", "user_id") },
+    Migration { version: 7, name: "expires_at", sql: include_str!("../../migrations/0007_expires_at.sql"), marker: ("backup_requests", "expires_at") },
+    Migration { version: 8, name: "job_claims", sql: include_str!("../../migrations/0008_job_claims.sql"), marker: ("backup_requests", "claim_id") },
+    Migration { version: 9, name: "retries", sql: include_str!("../../migrations/0009_retries.sql"), marker: ("backup_requests", "attempts") },
+    Migration { version: 10, name: "durations", sql: include_str!("../../migrations/0010_durations.sql"), marker: ("backup_requests", "started_at") },
+    Migration { version: 11, name: "worker_heartbeats", sql: include_str!("../../migrations/0011_worker_heartbeats.sql"), marker: ("worker_heartbeats", "worker_id") },
+    Migration { version: 12, name: "request_ids", sql: include_str!("../../migrations/0012_request_ids.sql"), marker: ("backup_requests", "request_id") },
+];
+
+fn has_column(c: &Connection, table: &str, column: &str) -> Result<bool, Error> {
+    let mut stmt = c.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map(params![], |row| row.get::<_, String>(1))?;
+
+    for name in columns {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn record(c: &Connection, migration: &Migration) -> Result<(), Error> {
+    c.execute("INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+              params![migration.version, migration.name, &time::get_time()])?;
+    Ok(())
+}
+
+// Databases created with the old `schema.sql` have no `schema_version`, their version is the
+// latest migration they already include
+fn detect_version(c: &Connection) -> Result<u32, Error> {
+    let mut version = 0;
+
+    for migration in MIGRATIONS {
+        let (table, column) = migration.marker;
+
+        if has_column(c, table, column)? {
+            version = migration.version;
+        } else {
+            break;
+        }
+    }
+
+    Ok(version)
+}
+
+// `None` until `schema_version` exists and has a row
+fn recorded_version(c: &Connection) -> Result<Option<u32>, Error> {
+    if !has_column(c, "schema_version", "version")? {
+        return Ok(None);
+    }
+
+    let version: Option<u32> = c.query_row("SELECT max(version) FROM schema_version", params![], |row| row.get(0))
+        .optional()?
+        .flatten();
+
+    Ok(version)
+}
+
+// Creates `schema_version`, recording the migrations found by `detect_version` for databases created before it
+fn create_version_table(c: &Connection) -> Result<(), Error> {
+    c.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (\
+                     version    INTEGER PRIMARY KEY, \
+                     name       TEXT NOT NULL, \
+                     applied_at TEXT NOT NULL)")?;
+
+    if recorded_version(c)?.is_some() {
+        return Ok(());
+    }
+
+    let version = detect_version(c)?;
+
+    for migration in MIGRATIONS.iter().take_while(|m| m.version <= version) {
+        record(c, migration)?;
+    }
+
+    if version > 0 {
+        log::info!("Found existing database without schema_version, using version {}", version);
+    }
+
+    Ok(())
+}
+
+// Read only, the database is not changed until `migrate`
+pub fn current_version(c: &Connection) -> Result<u32, Error> {
+    match recorded_version(c)? {
+        Some(version) => Ok(version),
+        None => detect_version(c),
+    }
+}
+
+pub fn pending(c: &Connection) -> Result<Vec<&'static Migration>, Error> {
+    let version = current_version(c)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+// Applies every pending migration, each one in its own transaction. Returns the applied versions.
+// Transactions take the write lock before reading the version, so a server and a daemon starting
+// together apply each migration once, the other one waits for it up to the busy timeout
+pub fn migrate(c: &mut Connection) -> Result<Vec<u32>, Error> {
+    c.busy_timeout(std::time::Duration::from_secs(30))?;
+
+    let mut applied = vec![];
+
+    loop {
+        let tx = c.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        create_version_table(&tx)?;
+
+        let version = current_version(&tx)?;
+
+        let migration = match MIGRATIONS.iter().find(|m| m.version > version) {
+            Some(migration) => migration,
+            None => {
+                tx.commit()?;
+                break;
+            }
+        };
+
+        tx.execute_batch(migration.sql)?;
+        record(&tx, migration)?;
+        tx.commit()?;
+
+        log::info!("Applied migration {} {}", migration.version, migration.name);
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+// Run on startup by the server and the daemon
+pub fn run(pool: &Pool) -> Result<(), Error> {
+    let mut c = pool.get()?;
+    let applied = migrate(&mut c)?;
+
+    log::info!("Database at version {}, applied {} migrations", current_version(&c)?, applied.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latest() -> u32 {
+        MIGRATIONS.last().unwrap().version
+    }
+
+    #[test]
+    fn test_migrates_new_database() -> Result<(), Error> {
+        let mut c = Connection::open_in_memory()?;
+
+        let applied = migrate(&mut c)?;
+
+        assert_eq!(applied, (1..=latest()).collect::<Vec<_>>());
+        assert_eq!(current_version(&c)?, latest());
+        assert!(has_column(&c, "backup_requests", "expires_at")?);
+        assert!(migrate(&mut c)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_detects_baseline_database() -> Result<(), Error> {
+        let mut c = Connection::open_in_memory()?;
+        c.execute_batch(MIGRATIONS[0].sql)?;
+        c.execute("INSERT INTO backup_requests (id, token, created_at, status) VALUES ('id', '{}', 'now', 'Pending')", params![])?;
+
+        let applied = migrate(&mut c)?;
+
+        assert_eq!(applied, (2..=latest()).collect::<Vec<_>>());
+
+        let options: String = c.query_row("SELECT options FROM backup_requests", params![], |row| row.get(0))?;
+        assert_eq!(options, r#"{"collections":["albums","playlists"]}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detects_partially_migrated_database() -> Result<(), Error> {
+        let mut c = Connection::open_in_memory()?;
+
+        for migration in MIGRATIONS.iter().take(3) {
+            c.execute_batch(migration.sql)?;
+        }
+
+        assert_eq!(current_version(&c)?, 3);
+        assert_eq!(migrate(&mut c)?, (4..=latest()).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_current_version_does_not_change_database() -> Result<(), Error> {
+        let c = Connection::open_in_memory()?;
+        c.execute_batch(MIGRATIONS[0].sql)?;
+
+        assert_eq!(current_version(&c)?, 1);
+        assert_eq!(pending(&c)?.len(), MIGRATIONS.len() - 1);
+        assert!(!has_column(&c, "schema_version", "version")?);
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use actix_web::{App, http, HttpResponse, HttpServer, middleware, web};
+use actix_web::{App, http, HttpRequest, HttpResponse, HttpServer, middleware, web};
+use actix_web::dev::Service;
+use actix_web::cookie::SameSite;
+use actix_session::{CookieSession, Session};
 
 use r2d2_sqlite::SqliteConnectionManager;
 use rspotify::spotify::oauth2::{SpotifyOAuth, TokenInfo};
@@ -6,17 +9,46 @@ use rspotify::spotify::oauth2::{SpotifyOAuth, TokenInfo};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::server::db::{BackupRequest, Pool, Connection};
+use crate::server::db::{BackupRequest, BackupSchedule, Frequency, Pool, Connection, User};
+use crate::server::crypto::{Keyring, LinkKey};
+use crate::server::store::{build_store, BackupStore};
+use std::sync::Arc;
 use crate::spotify::*;
-use crate::config::Config;
+use crate::config::{Config, ListenAddr, RetentionLimits, TlsConfig};
+use crate::progress::Progress;
+use crate::options::{BackupOptions, Collection, PlaylistFilter};
 use tera::Tera;
 
+// Set on every request and response, kept if a proxy in front of the server already set it
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// `middleware::Logger` default format, starting with the request id. See `logging::split_request_id`
+const HTTP_LOG_FORMAT: &str = "request_id=%{x-request-id}i %a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T";
+
+// Ids from proxies end up in logs and in the db, anything unusual is replaced
+fn request_id(req: &actix_web::dev::ServiceRequest) -> String {
+    let valid = |id: &&str| !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(valid)
+        .map(|id| id.to_owned())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
 mod db;
+mod crypto;
+mod migrations;
+mod admin;
+mod health;
+mod assets;
+pub mod store;
 pub mod daemon;
 
 mod app {
     use super::*;
     use crate::server::db::RequestStatus;
+    use std::io::Read;
     use tera::Context;
 
     #[derive(Clone)]
@@ -34,88 +66,684 @@ mod app {
             ctx.insert("base_path", &self.base_path);
             self.tera.render(tmpl, &ctx).map_err(failure::Error::from)
         }
+
+        pub fn has_template(&self, name: &str) -> bool {
+            self.tera.get_template_names().any(|t| t == name)
+        }
     }
 
+    // Spotify sends `error` instead of `code` when the user does not allow access
     #[derive(Deserialize)]
     pub struct SpotifyApiCallbackParams {
-        code: String
+        code: Option<String>,
+        state: Option<String>,
+        error: Option<String>,
+    }
+
+    // Sent to spotify in the oauth `state` and back to us in `callback`
+    #[derive(Serialize, Deserialize, Default)]
+    pub struct AuthState {
+        pub options: BackupOptions,
+        #[serde(default)]
+        pub frequency: Option<Frequency>,
+        // only log the user in, without starting a backup
+        #[serde(default)]
+        pub login: bool,
+        #[serde(default)]
+        pub retention_hours: Option<u32>,
+    }
+
+    const SESSION_USER_ID: &str = "user_id";
+
+    // The `state` sent to spotify by the last login or backup started in this session
+    const SESSION_OAUTH_STATE: &str = "oauth_state";
+
+    // The PKCE verifier of that same authorization, if there is no client secret
+    const SESSION_OAUTH_VERIFIER: &str = "oauth_verifier";
+
+    // Sends the user to spotify, `callback` only accepts the state saved here
+    pub fn authorize_redirect(session: &Session, spotify_oauth: &SpotifyOAuth, scope: &str, auth_state: &AuthState) -> Result<HttpResponse, actix_web::error::Error> {
+        let auth = build_user_redirect_uri(spotify_oauth, scope, auth_state)?;
+        session.set(SESSION_OAUTH_STATE, auth.state)?;
+
+        match auth.verifier {
+            Some(verifier) => session.set(SESSION_OAUTH_VERIFIER, verifier)?,
+            None => session.remove(SESSION_OAUTH_VERIFIER),
+        }
+
+        Ok(redirect(auth.url))
+    }
+
+    // The state can only be used once
+    fn check_oauth_state(session: &Session, state: Option<&str>) -> bool {
+        let expected = session.get::<String>(SESSION_OAUTH_STATE).ok().flatten();
+        session.remove(SESSION_OAUTH_STATE);
+
+        match (expected, state) {
+            (Some(expected), Some(state)) =>
+                ring::constant_time::verify_slices_are_equal(expected.as_bytes(), state.as_bytes()).is_ok(),
+            _ => false,
+        }
+    }
+
+    pub fn error_page(renderer: &DefaultRenderer, status: http::StatusCode, message: &str) -> HttpResponse {
+        let mut ctx = Context::new();
+        ctx.insert("message", message);
+
+        match renderer.render("error.html", &mut ctx) {
+            Ok(body) => HttpResponse::build(status).content_type("text/html").body(body),
+            Err(err) => {
+                log::error!("Could not render error page: {}", err);
+                HttpResponse::build(status).body(message.to_owned())
+            }
+        }
     }
 
+    // Scopes are not needed to read the user id
+    const LOGIN_SCOPE: &str = "";
+
     #[derive(Serialize)]
     pub struct BackupResponse {
         pub backup_id: Uuid,
         pub missing: bool,
         pub executed: bool,
         pub completed: bool,
+        pub cancelled: bool,
+        // only set for the owner of a pending or running backup
+        pub cancellable: bool,
         pub error: Option<String>,
+        pub warnings: u32,
+        pub progress: Option<Progress>,
+        pub schedule_id: Option<Uuid>,
+        pub expires_at: Option<String>,
+        // only set for the owner of the backup
+        pub download_url: Option<String>,
     }
 
-    pub async fn index(renderer: web::Data<DefaultRenderer>) -> Result<HttpResponse, actix_web::error::Error> {
-        let body = renderer.render("index.html", &mut Context::new())?;
+    impl BackupResponse {
+        pub fn found(backup: BackupRequest, status: RequestStatus) -> BackupResponse {
+            BackupResponse {
+                backup_id: backup.id,
+                missing: false,
+                executed: status.executed(),
+                completed: status.completed(),
+                cancelled: status == RequestStatus::Cancelled,
+                cancellable: false,
+                error: backup.last_error,
+                warnings: backup.warning_count,
+                progress: backup.progress,
+                schedule_id: backup.schedule_id,
+                expires_at: backup.expires_at.map(format_time),
+                download_url: None,
+            }
+        }
+
+        pub fn missing(uuid: Uuid) -> BackupResponse {
+            BackupResponse {
+                backup_id: uuid,
+                missing: true,
+                executed: false,
+                completed: false,
+                cancelled: false,
+                cancellable: false,
+                error: "backup does not exist".to_string().into(),
+                warnings: 0,
+                progress: None,
+                schedule_id: None,
+                expires_at: None,
+                download_url: None,
+            }
+        }
+
+        // Once a backup reaches one of these states it will not change until it expires
+        pub fn is_final(&self) -> bool {
+            self.missing || self.executed || self.completed || self.cancelled
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct ScheduleRun {
+        pub backup_id: Uuid,
+        pub created_at: String,
+        pub status: RequestStatus,
+        pub available: bool,
+        pub download_url: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    pub struct ScheduleResponse {
+        pub schedule_id: Uuid,
+        pub missing: bool,
+        pub frequency: Option<Frequency>,
+        pub next_run_at: Option<String>,
+        pub error: Option<String>,
+        pub runs: Vec<ScheduleRun>,
+        // only the owner can stop the schedule
+        pub owner: bool,
+    }
+
+    #[derive(Serialize)]
+    pub struct RetentionChoice {
+        pub hours: u32,
+        pub label: String,
+    }
+
+    impl RetentionChoice {
+        fn new(hours: u32) -> RetentionChoice {
+            let label = match hours {
+                1 => "1 hour".to_owned(),
+                24 => "1 day".to_owned(),
+                h if h % 24 == 0 => format!("{} days", h / 24),
+                h => format!("{} hours", h),
+            };
+
+            RetentionChoice { hours, label }
+        }
+    }
+
+    #[derive(Serialize)]
+    pub struct HistoryEntry {
+        pub backup_id: Uuid,
+        pub created_at: String,
+        pub status: RequestStatus,
+        pub available: bool,
+        pub download_url: Option<String>,
+        pub warnings: u32,
+        pub size: Option<String>,
+        pub schedule_id: Option<Uuid>,
+    }
+
+    #[derive(Serialize)]
+    pub struct HistorySchedule {
+        pub schedule_id: Uuid,
+        pub frequency: Frequency,
+        pub next_run_at: String,
+    }
+
+    #[derive(Serialize)]
+    pub struct HistoryResponse {
+        pub logged_in: bool,
+        pub user_name: Option<String>,
+        pub backups: Vec<HistoryEntry>,
+        pub schedules: Vec<HistorySchedule>,
+    }
+
+    pub fn format_time(t: time::Timespec) -> String {
+        time::at_utc(t).rfc3339().to_string()
+    }
+
+    pub fn format_size(bytes: i64) -> String {
+        let units = ["KB", "MB", "GB"];
+        let mut size = bytes as f64;
+
+        if size < 1024.0 {
+            return format!("{} bytes", bytes);
+        }
+
+        let mut unit = units[0];
+        for u in units.iter() {
+            size /= 1024.0;
+            unit = u;
+            if size < 1024.0 {
+                break;
+            }
+        }
+
+        format!("{:.1} {}", size, unit)
+    }
+
+    // How long signed download links are valid, in seconds
+    const DOWNLOAD_LINK_EXPIRY: i64 = 60 * 60;
+
+    pub fn download_url(base_path: &str, key: &LinkKey, id: Uuid) -> String {
+        let expires = time::get_time().sec + DOWNLOAD_LINK_EXPIRY;
+        format!("{}/downloads/{}.json?expires={}&signature={}", base_path, id, expires, key.sign(id, expires))
+    }
+
+    // Backups made before users were saved have no owner, those can only be downloaded with a signed link
+    pub fn is_owner(session: &Session, owner: &Option<String>) -> bool {
+        match (owner, session_user(session)) {
+            (Some(owner), Some(user)) => *owner == user,
+            _ => false,
+        }
+    }
+
+    pub fn session_user(session: &Session) -> Option<String> {
+        match session.get::<String>(SESSION_USER_ID) {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                log::warn!("Could not read user from session: {}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn index(session: Session, renderer: web::Data<DefaultRenderer>, keyring: web::Data<Option<Arc<Keyring>>>, retention: web::Data<RetentionLimits>) -> Result<HttpResponse, actix_web::error::Error> {
+        let mut ctx = Context::new();
+        ctx.insert("retention", retention.get_ref());
+        ctx.insert("retention_choices", &retention.choices().into_iter().map(|h| RetentionChoice::new(h)).collect::<Vec<_>>());
+        ctx.insert("schedules_enabled", &keyring.is_some());
+        ctx.insert("logged_in", &session_user(&session).is_some());
+
+        let body = renderer.render("index.html", &mut ctx)?;
         Ok(HttpResponse::Ok().body(body))
     }
 
-    pub async fn find_backup(c: Connection, uuid: Uuid) -> Option<(BackupRequest, RequestStatus)> {
-        web::block(move || { db::backup_request::find_with_status(c, uuid) }).await.unwrap()
+    pub async fn find_backup(c: Connection, keyring: Option<Arc<Keyring>>, uuid: Uuid) -> Option<(BackupRequest, RequestStatus)> {
+        web::block(move || { db::backup_request::find_with_status(c, keyring.as_deref(), uuid) }).await.unwrap()
     }
 
 
-    pub async fn backup_get(path: web::Path<(Uuid, )>, pool: web::Data<Pool>, renderer: web::Data<DefaultRenderer>) -> Result<HttpResponse, failure::Error> {
+    pub async fn backup_get(path: web::Path<(Uuid, )>, session: Session, pool: web::Data<Pool>, renderer: web::Data<DefaultRenderer>, link_key: web::Data<LinkKey>, keyring: web::Data<Option<Arc<Keyring>>>) -> Result<HttpResponse, failure::Error> {
         let uuid = path.0;
 
-        if let Some((backup, status)) = find_backup(pool.get()?, uuid).await {
-            let resp = BackupResponse {
-                backup_id: uuid,
+        if let Some((backup, status)) = find_backup(pool.get()?, keyring.get_ref().clone(), uuid).await {
+            let owner = is_owner(&session, &backup.user_id);
+            let available = status == RequestStatus::Executed;
+            let cancellable = status.cancellable();
+
+            let mut resp = BackupResponse::found(backup, status);
+            resp.cancellable = owner && cancellable;
+
+            if owner && available {
+                resp.download_url = Some(download_url(&renderer.base_path, &link_key, uuid));
+            }
+
+            let body = renderer.render("backup.html", &mut Context::from_serialize(resp)?)?;
+            Ok(HttpResponse::Ok().body(body))
+        } else {
+            let resp = BackupResponse::missing(uuid);
+
+            let body = renderer.render("backup.html", &mut Context::from_serialize(resp)?)?;
+            Ok(HttpResponse::NotFound().body(body))
+        }
+    }
+
+    pub async fn schedule_get(path: web::Path<(Uuid, )>, session: Session, pool: web::Data<Pool>, renderer: web::Data<DefaultRenderer>, link_key: web::Data<LinkKey>, keyring: web::Data<Option<Arc<Keyring>>>) -> Result<HttpResponse, failure::Error> {
+        let uuid = path.0;
+        let p = pool.get_ref().clone();
+        let keyring = keyring.get_ref().clone();
+
+        let found = web::block(move || -> Result<_, failure::Error> {
+            match db::backup_schedule::find(p.get()?, uuid)? {
+                Some(schedule) => {
+                    let runs = db::backup_request::find_for_schedule(p.get()?, keyring.as_deref(), uuid)?;
+                    Ok(Some((schedule, runs)))
+                },
+                None => Ok(None)
+            }
+        }).await.map_err(|err| failure::format_err!("{}", err))?;
+
+        if let Some((schedule, runs)) = found {
+            let owner = is_owner(&session, &Some(schedule.user_id.clone()));
+
+            let runs = runs.into_iter().map(|(req, status)| {
+                let available = status == RequestStatus::Executed;
+
+                ScheduleRun {
+                    backup_id: req.id,
+                    created_at: format_time(req.time_created),
+                    available,
+                    download_url: if owner && available { Some(download_url(&renderer.base_path, &link_key, req.id)) } else { None },
+                    status,
+                }
+            }).collect();
+
+            let resp = ScheduleResponse {
+                schedule_id: uuid,
                 missing: false,
-                executed:  status.executed(),
-                completed: status.completed(),
-                error: backup.last_error,
+                frequency: Some(schedule.frequency),
+                next_run_at: Some(format_time(schedule.next_run_at)),
+                error: schedule.last_error,
+                runs,
+                owner,
             };
 
-            let body = renderer.render("backup.html", &mut Context::from_serialize(resp)?)?;
+            let body = renderer.render("schedule.html", &mut Context::from_serialize(resp)?)?;
             Ok(HttpResponse::Ok().body(body))
         } else {
-            let resp = BackupResponse {
-                backup_id: uuid,
+            let resp = ScheduleResponse {
+                schedule_id: uuid,
                 missing: true,
-                executed:  false,
-                completed: false,
-                error: "backup does not exist".to_string().into(),
+                frequency: None,
+                next_run_at: None,
+                error: None,
+                runs: vec![],
+                owner: false,
             };
 
-            let body = renderer.render("backup.html", &mut Context::from_serialize(resp)?)?;
+            let body = renderer.render("schedule.html", &mut Context::from_serialize(resp)?)?;
             Ok(HttpResponse::NotFound().body(body))
         }
     }
 
-    async fn save_backup_request(pool: &Pool, oauth_code: &TokenInfo) -> Uuid {
+    pub async fn history_get(session: Session, pool: web::Data<Pool>, renderer: web::Data<DefaultRenderer>, link_key: web::Data<LinkKey>, keyring: web::Data<Option<Arc<Keyring>>>) -> Result<HttpResponse, failure::Error> {
+        let user_id = match session_user(&session) {
+            Some(user_id) => user_id,
+            None => {
+                let resp = HistoryResponse { logged_in: false, user_name: None, backups: vec![], schedules: vec![] };
+                let body = renderer.render("history.html", &mut Context::from_serialize(resp)?)?;
+                return Ok(HttpResponse::Ok().body(body));
+            }
+        };
+
+        let p = pool.get_ref().clone();
+        let keyring = keyring.get_ref().clone();
+
+        let (user, backups, schedules) = web::block(move || -> Result<_, failure::Error> {
+            let user = db::user::find(p.get()?, &user_id)?;
+            let backups = db::backup_request::find_for_user(p.get()?, keyring.as_deref(), &user_id)?;
+            let schedules = db::backup_schedule::find_for_user(p.get()?, &user_id)?;
+            Ok((user, backups, schedules))
+        }).await.map_err(|err| failure::format_err!("{}", err))?;
+
+        let backups = backups.into_iter().map(|(req, status)| {
+            let available = status == RequestStatus::Executed;
+
+            HistoryEntry {
+                backup_id: req.id,
+                created_at: format_time(req.time_created),
+                available,
+                download_url: if available { Some(download_url(&renderer.base_path, &link_key, req.id)) } else { None },
+                status,
+                warnings: req.warning_count,
+                size: req.file_size.map(format_size),
+                schedule_id: req.schedule_id,
+            }
+        }).collect();
+
+        let schedules = schedules.into_iter().map(|schedule| HistorySchedule {
+            schedule_id: schedule.id,
+            frequency: schedule.frequency,
+            next_run_at: format_time(schedule.next_run_at),
+        }).collect();
+
+        let resp = HistoryResponse {
+            logged_in: true,
+            user_name: user.map(|u| u.display_name.unwrap_or(u.id)),
+            backups,
+            schedules,
+        };
+
+        let body = renderer.render("history.html", &mut Context::from_serialize(resp)?)?;
+        Ok(HttpResponse::Ok().body(body))
+    }
+
+    // How long presigned download urls are valid
+    const DOWNLOAD_URL_EXPIRY: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+    const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+    type BackupReader = Box<dyn Read + Send>;
+
+    async fn next_chunk(reader: Option<BackupReader>) -> Option<(Result<actix_web::web::Bytes, actix_web::Error>, Option<BackupReader>)> {
+        let mut reader = reader?;
+
+        let read = web::block(move || -> Result<_, std::io::Error> {
+            let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+            let n = reader.read(&mut buf)?;
+            buf.truncate(n);
+            Ok((buf, reader))
+        }).await;
+
+        match read {
+            Ok((buf, _)) if buf.is_empty() =>
+                None,
+            Ok((buf, reader)) =>
+                Some((Ok(actix_web::web::Bytes::from(buf)), Some(reader))),
+            Err(err) => {
+                log::error!("Could not read backup file: {}", err);
+                Some((Err(actix_web::error::ErrorInternalServerError(err)), None))
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct DownloadParams {
+        expires: Option<i64>,
+        signature: Option<String>,
+    }
+
+    // `name` is `<backup id>.json`. Needs either a signed link or the session of the user who made the backup,
+    // anything else is a 404 so it's not possible to find out which backups exist
+    pub async fn download(path: web::Path<(String, )>, params: web::Query<DownloadParams>, session: Session, pool: web::Data<Pool>,
+                          store: web::Data<Arc<dyn BackupStore>>, link_key: web::Data<LinkKey>, keyring: web::Data<Option<Arc<Keyring>>>) -> Result<HttpResponse, actix_web::error::Error> {
+        let uuid = path.0.trim_end_matches(".json").parse::<Uuid>()
+            .map_err(|_| actix_web::error::ErrorNotFound("backup not found"))?;
+
+        let (key, owner) = match find_backup(pool.get().map_err(actix_web::error::ErrorInternalServerError)?, keyring.get_ref().clone(), uuid).await {
+            Some((BackupRequest { file: Some(file), user_id, .. }, RequestStatus::Executed)) => (file.to_string_lossy().to_string(), user_id),
+            _ => {
+                log::info!("Download of backup {} denied, backup not available", uuid);
+                return Err(actix_web::error::ErrorNotFound("backup not found"))
+            },
+        };
+
+        let signed = match (params.expires, params.signature.as_ref()) {
+            (Some(expires), Some(signature)) => link_key.verify(uuid, expires, signature, time::get_time().sec),
+            _ => false,
+        };
+
+        if signed {
+            log::info!("Backup {} downloaded with a signed link", uuid);
+        } else if is_owner(&session, &owner) {
+            log::info!("Backup {} downloaded by its owner {}", uuid, owner.unwrap_or_default());
+        } else {
+            log::warn!("Download of backup {} denied, no valid signature or session", uuid);
+            return Err(actix_web::error::ErrorNotFound("backup not found"));
+        }
+
+        let s = store.get_ref().clone();
+        let k = key.clone();
+        let url = web::block(move || s.download_url(&k, DOWNLOAD_URL_EXPIRY)).await?;
+
+        if let Some(url) = url {
+            return Ok(redirect(url));
+        }
+
+        let s = store.get_ref().clone();
+        let reader = web::block(move || s.open(&key)).await?;
+
+        Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .set_header(http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"spotify-backup-{}.json\"", uuid))
+            .streaming(Box::pin(futures::stream::unfold(Some(reader), next_chunk))))
+    }
+
+    pub async fn login(session: Session, spotify_oauth: web::Data<SpotifyOAuth>) -> Result<HttpResponse, actix_web::error::Error> {
+        let auth_state = AuthState { login: true, ..AuthState::default() };
+        authorize_redirect(&session, &spotify_oauth, LOGIN_SCOPE, &auth_state)
+    }
+
+    pub async fn logout(session: Session, renderer: web::Data<DefaultRenderer>) -> HttpResponse {
+        session.purge();
+        redirect(format!("{}/", renderer.base_path))
+    }
+
+    fn blocking_error(err: actix_web::error::BlockingError<failure::Error>) -> failure::Error {
+        match err {
+            actix_web::error::BlockingError::Error(err) => err,
+            actix_web::error::BlockingError::Canceled => failure::format_err!("blocking operation was cancelled"),
+        }
+    }
+
+    // Creates the user on the first login
+    async fn save_user(pool: &Pool, token: &TokenInfo) -> Result<String, failure::Error> {
+        let p = pool.clone();
+        let token = token.clone();
+
+        web::block(move || -> Result<String, failure::Error> {
+            let me = build_spotify_client(token).me()?;
+            let now = time::get_time();
+
+            let user = User {
+                id: me.id,
+                display_name: me.display_name,
+                time_created: now,
+                last_login: now,
+            };
+
+            db::user::save_login(p.get()?, &user)?;
+            Ok(user.id)
+        }).await.map_err(blocking_error)
+    }
+
+    // The first run is enqueued right away by `callback`
+    async fn save_backup_schedule(pool: &Pool, key: &Arc<Keyring>, token: &TokenInfo, user_id: &str, frequency: Frequency, options: &BackupOptions) -> Result<Uuid, failure::Error> {
+        let p = pool.clone();
+        let key = key.clone();
+        let token = token.clone();
+        let user_id = user_id.to_owned();
+        let options = options.clone();
+
+        web::block(move || -> Result<Uuid, failure::Error> {
+            let refresh_token = token.refresh_token.clone()
+                .ok_or_else(|| failure::format_err!("spotify did not send a refresh token"))?;
+
+            let now = time::get_time();
+
+            let schedule = BackupSchedule {
+                id: Uuid::new_v4(),
+                user_id,
+                refresh_token: key.seal(&refresh_token)?,
+                frequency,
+                options,
+                time_created: now,
+                next_run_at: now + frequency.interval(),
+                last_error: None,
+            };
+
+            db::backup_schedule::create(p.get()?, &schedule)
+        }).await.map_err(blocking_error)
+    }
+
+    async fn save_backup_request(pool: &Pool, keyring: &Option<Arc<Keyring>>, oauth_code: &TokenInfo, user_id: &str, options: BackupOptions, schedule_id: Option<Uuid>, retention_hours: Option<u32>, request_id: Option<String>) -> Result<Uuid, failure::Error> {
         let id = Uuid::new_v4();
         let p = pool.clone();
+        let keyring = keyring.clone();
         let token = oauth_code.clone();
+        let now = time::now_utc().to_timespec();
 
         let req = BackupRequest {
             id,
             token,
-            time_created: time::now_utc().to_timespec(),
+            time_created: now,
             file: None,
             last_error: None,
+            warning_count: 0,
+            progress: None,
+            options,
+            schedule_id,
+            user_id: Some(user_id.to_owned()),
+            file_size: None,
+            expires_at: retention_hours.map(|h| now + time::Duration::hours(h as i64)),
+            attempts: 0,
+            next_attempt_at: None,
+            request_id,
         };
 
-        web::block(move || { db::backup_request::create(p.get()?, &req) }).await.unwrap()
+        web::block(move || { db::backup_request::create(p.get()?, keyring.as_deref(), &req) }).await.map_err(blocking_error)
     }
 
-    async fn get_access_token(spotify_oauth: &SpotifyOAuth, code: &str) -> TokenInfo {
+    // `None` if spotify did not accept the code, eg. it expired or was already used
+    async fn get_access_token(spotify_oauth: &SpotifyOAuth, code: &str, verifier: Option<String>) -> Option<TokenInfo> {
         let code_owned = code.to_string();
         let spotify_owned = spotify_oauth.clone();
 
-        web::block(move || {
-            spotify_owned.get_access_token(&code_owned).ok_or("Received token was not valid")
-        }).await.unwrap()
+        let token = web::block(move || {
+            crate::spotify::get_access_token(&spotify_owned, &code_owned, verifier.as_ref().map(|v| v.as_str()))
+        }).await;
+
+        match token {
+            Ok(token) => Some(token),
+            Err(err) => {
+                log::warn!("Could not get spotify token: {}", err);
+                None
+            }
+        }
     }
 
-    pub async fn callback(renderer: web::Data<DefaultRenderer>, info: web::Query<SpotifyApiCallbackParams>, db: web::Data<Pool>, spotify_oauth: web::Data<SpotifyOAuth>) -> HttpResponse {
-        let token = get_access_token(&spotify_oauth, &info.code).await;
-        let uuid = save_backup_request(&db, &token).await;
+    pub async fn callback(req: HttpRequest, renderer: web::Data<DefaultRenderer>, session: Session, info: web::Query<SpotifyApiCallbackParams>, db: web::Data<Pool>, spotify_oauth: web::Data<SpotifyOAuth>, keyring: web::Data<Option<Arc<Keyring>>>, retention: web::Data<RetentionLimits>) -> HttpResponse {
+        let verifier = session.get::<String>(SESSION_OAUTH_VERIFIER).ok().flatten();
+        session.remove(SESSION_OAUTH_VERIFIER);
+
+        if !check_oauth_state(&session, info.state.as_ref().map(|s| s.as_str())) {
+            log::warn!("Rejected spotify callback with an unknown state");
+            return error_page(&renderer, http::StatusCode::BAD_REQUEST, "This login link is not valid anymore, please start again.");
+        }
+
+        let code = match (&info.code, &info.error) {
+            (_, Some(error)) if error == "access_denied" =>
+                return error_page(&renderer, http::StatusCode::OK, "You did not allow access to your Spotify account, no backup was made."),
+            (_, Some(error)) => {
+                log::warn!("Spotify authorization failed: {}", error);
+                return error_page(&renderer, http::StatusCode::BAD_GATEWAY, "Spotify could not authorize access to your account, please try again.");
+            },
+            (Some(code), None) => code,
+            (None, None) =>
+                return error_page(&renderer, http::StatusCode::BAD_REQUEST, "Spotify did not send an authorization code, please try again."),
+        };
+
+        let token = match get_access_token(&spotify_oauth, code, verifier).await {
+            Some(token) => token,
+            None => return error_page(&renderer, http::StatusCode::BAD_REQUEST, "Spotify did not accept the authorization code, please try again."),
+        };
+
+        let auth_state: AuthState = info.state.as_ref()
+            .and_then(|state| payload_from_state(state).ok())
+            .unwrap_or_default();
+
+        let user_id = match save_user(&db, &token).await {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                log::error!("Could not save user: {}", err);
+                return error_page(&renderer, http::StatusCode::BAD_GATEWAY, "Could not get your account from Spotify, please try again.");
+            }
+        };
+
+        if let Err(err) = session.set(SESSION_USER_ID, &user_id) {
+            log::warn!("Could not save user in session: {}", err);
+        }
+
+        if auth_state.login {
+            return redirect(format!("{}/backups", renderer.base_path));
+        }
+
+        let schedule_id = match (auth_state.frequency, keyring.get_ref()) {
+            (Some(frequency), Some(key)) => match save_backup_schedule(&db, key, &token, &user_id, frequency, &auth_state.options).await {
+                Ok(schedule_id) => Some(schedule_id),
+                Err(err) => {
+                    log::error!("Could not save backup schedule for user {}: {}", user_id, err);
+                    return error_page(&renderer, http::StatusCode::INTERNAL_SERVER_ERROR, "Could not save your recurring backup, please try again.");
+                }
+            },
+            _ =>
+                None
+        };
+
+        let mut request_token = token.clone();
+        if schedule_id.is_some() {
+            // already saved, encrypted, with the schedule
+            request_token.refresh_token = None;
+        }
+
+        // scheduled backups are kept by count instead. The state was sent through the user's
+        // browser, so the retention is checked again
+        let retention_hours = match schedule_id {
+            Some(_) => None,
+            None => Some(retention.hours(auth_state.retention_hours).unwrap_or(retention.default_hours)),
+        };
+
+        let request_id = req.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()).map(|id| id.to_owned());
+
+        let uuid = match save_backup_request(&db, keyring.get_ref(), &request_token, &user_id, auth_state.options, schedule_id, retention_hours, request_id).await {
+            Ok(uuid) => uuid,
+            Err(err) => {
+                log::error!("Could not save backup request for user {}: {}", user_id, err);
+                return error_page(&renderer, http::StatusCode::INTERNAL_SERVER_ERROR, "Could not start your backup, please try again.");
+            }
+        };
+        log::info!("Created backup {} for user {}", uuid, user_id);
         redirect(format!("{}/backups/{}", renderer.base_path, uuid))
     }
 
@@ -126,67 +754,461 @@ mod app {
 
 mod api {
     use super::*;
+    use actix_web::web::Bytes;
+    use std::time::Duration;
 
-    pub async fn backup_get(path: web::Path<(Uuid, )>, pool: web::Data<Pool>) -> HttpResponse {
-        let uuid = path.0;
+    const EVENTS_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-        if let Some((req, status)) = app::find_backup(pool.get().unwrap(), uuid).await {
-            let response = app::BackupResponse {
-                backup_id: req.id,
-                missing: false,
-                executed: status.executed(),
-                completed: status.completed(),
-                error: req.last_error
+    // Send a comment every ~15 seconds without changes so proxies don't close the stream
+    const EVENTS_KEEP_ALIVE_POLLS: u32 = 15;
+
+    struct EventsState {
+        pool: Pool,
+        keyring: Option<Arc<Keyring>>,
+        uuid: Uuid,
+        last: Option<String>,
+        idle_polls: u32,
+        done: bool,
+    }
+
+    // The daemon writes status and progress to the db, so we poll the db here and only
+    // push an event to the client when the backup changed
+    async fn next_event(mut state: EventsState) -> Option<(Result<Bytes, actix_web::Error>, EventsState)> {
+        if state.done {
+            return None;
+        }
+
+        loop {
+            if state.last.is_some() {
+                actix_rt::time::delay_for(EVENTS_POLL_INTERVAL).await;
+            }
+
+            let c = match state.pool.get() {
+                Ok(c) => c,
+                Err(err) => {
+                    log::error!("Could not get db connection for backup events: {}", err);
+                    return None;
+                }
+            };
+
+            let response = match app::find_backup(c, state.keyring.clone(), state.uuid).await {
+                Some((req, status)) => app::BackupResponse::found(req, status),
+                None => app::BackupResponse::missing(state.uuid),
             };
 
+            let json = serde_json::to_string(&response).ok()?;
+
+            if state.last.as_ref() != Some(&json) {
+                state.done = response.is_final();
+                state.last = Some(json.clone());
+                state.idle_polls = 0;
+
+                let event = format!("event: status\ndata: {}\n\n", json);
+                return Some((Ok(Bytes::from(event)), state));
+            }
+
+            state.idle_polls += 1;
+
+            if state.idle_polls >= EVENTS_KEEP_ALIVE_POLLS {
+                state.idle_polls = 0;
+                return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), state));
+            }
+        }
+    }
+
+    pub async fn backup_events(path: web::Path<(Uuid, )>, pool: web::Data<Pool>, keyring: web::Data<Option<Arc<Keyring>>>) -> HttpResponse {
+        let state = EventsState {
+            pool: pool.get_ref().clone(),
+            keyring: keyring.get_ref().clone(),
+            uuid: path.0,
+            last: None,
+            idle_polls: 0,
+            done: false,
+        };
+
+        let events = futures::stream::unfold(state, next_event);
+
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .set_header(http::header::CACHE_CONTROL, "no-cache")
+            .streaming(Box::pin(events))
+    }
+
+    pub async fn backup_get(path: web::Path<(Uuid, )>, session: Session, pool: web::Data<Pool>, renderer: web::Data<app::DefaultRenderer>, link_key: web::Data<LinkKey>, keyring: web::Data<Option<Arc<Keyring>>>) -> HttpResponse {
+        let uuid = path.0;
+
+        if let Some((req, status)) = app::find_backup(pool.get().unwrap(), keyring.get_ref().clone(), uuid).await {
+            let owner = app::is_owner(&session, &req.user_id);
+            let available = status == db::RequestStatus::Executed;
+            let cancellable = status.cancellable();
+
+            let mut response = app::BackupResponse::found(req, status);
+            response.cancellable = owner && cancellable;
+
+            if owner && available {
+                response.download_url = Some(app::download_url(&renderer.base_path, &link_key, uuid));
+            }
+
             HttpResponse::Ok().json(response)
         } else {
             HttpResponse::NotFound().set_header("Content-Type", "json").finish()
         }
     }
 
-    pub async fn backup_start(spotify_oauth: web::Data<SpotifyOAuth>) -> Result<HttpResponse, actix_web::error::Error> {
-        let uri = build_user_redirect_uri(&spotify_oauth)?;
-        Ok(app::redirect(uri))
+    // Checkboxes from the start page, a missing field means the option was not selected.
+    // Playlist name patterns are comma separated
+    #[derive(Deserialize, Default)]
+    pub struct BackupStartParams {
+        albums: Option<String>,
+        playlists: Option<String>,
+        liked_songs: Option<String>,
+        followed_artists: Option<String>,
+        owned_only: Option<String>,
+        collaborative_only: Option<String>,
+        skip_spotify_owned: Option<String>,
+        include_playlists: Option<String>,
+        exclude_playlists: Option<String>,
+        frequency: Option<String>,
+        retention_hours: Option<String>,
+    }
+
+    fn split_patterns(patterns: &Option<String>) -> Vec<String> {
+        patterns.iter()
+            .flat_map(|p| p.split(','))
+            .map(|p| p.trim().to_owned())
+            .filter(|p| !p.is_empty())
+            .collect()
+    }
+
+    impl BackupStartParams {
+        fn options(&self) -> BackupOptions {
+            let selected = vec![
+                (Collection::Albums, &self.albums),
+                (Collection::Playlists, &self.playlists),
+                (Collection::LikedSongs, &self.liked_songs),
+                (Collection::FollowedArtists, &self.followed_artists),
+            ];
+
+            let collections = selected
+                .into_iter()
+                .filter(|(_, param)| param.is_some())
+                .map(|(c, _)| c)
+                .collect();
+
+            let playlist_filter = PlaylistFilter {
+                owned_only: self.owned_only.is_some(),
+                collaborative_only: self.collaborative_only.is_some(),
+                skip_spotify_owned: self.skip_spotify_owned.is_some(),
+                include: split_patterns(&self.include_playlists),
+                exclude: split_patterns(&self.exclude_playlists),
+            };
+
+            BackupOptions { playlist_filter, ..BackupOptions::new(collections) }
+        }
+
+        fn frequency(&self) -> Result<Option<Frequency>, actix_web::error::Error> {
+            match self.frequency.as_ref().map(|f| f.as_str()) {
+                None | Some("") | Some("once") => Ok(None),
+                Some("daily") => Ok(Some(Frequency::Daily)),
+                Some("weekly") => Ok(Some(Frequency::Weekly)),
+                Some(other) => Err(actix_web::error::ErrorBadRequest(format!("invalid frequency: {}", other))),
+            }
+        }
+
+        fn retention_hours(&self, limits: &RetentionLimits) -> Result<Option<u32>, actix_web::error::Error> {
+            let requested = match self.retention_hours.as_ref().map(|h| h.trim()) {
+                None | Some("") => None,
+                Some(h) => Some(h.parse::<u32>().map_err(|_| actix_web::error::ErrorBadRequest(format!("invalid retention: {}", h)))?),
+            };
+
+            limits.hours(requested)
+                .map(Some)
+                .ok_or_else(|| actix_web::error::ErrorBadRequest(format!("retention must be between 1 and {} hours", limits.max_hours)))
+        }
+    }
+
+    pub async fn backup_start(params: Option<web::Form<BackupStartParams>>, session: Session, spotify_oauth: web::Data<SpotifyOAuth>, keyring: web::Data<Option<Arc<Keyring>>>, retention: web::Data<RetentionLimits>) -> Result<HttpResponse, actix_web::error::Error> {
+        let params = params.map(|p| p.into_inner()).unwrap_or_default();
+        let frequency = params.frequency()?;
+        let retention_hours = params.retention_hours(&retention)?;
+
+        if frequency.is_some() && keyring.is_none() {
+            return Err(actix_web::error::ErrorBadRequest("recurring backups are not enabled"));
+        }
+
+        let auth_state = app::AuthState { options: params.options(), frequency, login: false, retention_hours };
+        app::authorize_redirect(&session, &spotify_oauth, &auth_state.options.scope(), &auth_state)
+    }
+
+    // A running backup stops at the next page fetched by the daemon, which also deletes any partial file
+    pub async fn backup_cancel(path: web::Path<(Uuid, )>, session: Session, pool: web::Data<Pool>, renderer: web::Data<app::DefaultRenderer>, keyring: web::Data<Option<Arc<Keyring>>>) -> Result<HttpResponse, actix_web::error::Error> {
+        let uuid = path.0;
+
+        let owner = match app::find_backup(pool.get().map_err(actix_web::error::ErrorInternalServerError)?, keyring.get_ref().clone(), uuid).await {
+            Some((req, _)) => req.user_id,
+            None => return Err(actix_web::error::ErrorNotFound("backup not found")),
+        };
+
+        // Same as downloads, a 404 so it's not possible to find out which backups exist
+        if !app::is_owner(&session, &owner) {
+            log::warn!("Cancel of backup {} denied, not the owner", uuid);
+            return Err(actix_web::error::ErrorNotFound("backup not found"));
+        }
+
+        let p = pool.get_ref().clone();
+
+        let cancelled = web::block(move || -> Result<bool, failure::Error> {
+            db::backup_request::cancel(p.get()?, uuid)
+        }).await?;
+
+        if cancelled {
+            log::info!("Cancelled backup {}", uuid);
+        }
+
+        Ok(app::redirect(format!("{}/backups/{}", renderer.base_path, uuid)))
+    }
+
+    pub async fn schedule_delete(path: web::Path<(Uuid, )>, session: Session, pool: web::Data<Pool>, renderer: web::Data<app::DefaultRenderer>) -> Result<HttpResponse, actix_web::error::Error> {
+        let uuid = path.0;
+        let p = pool.get_ref().clone();
+
+        let schedule = web::block(move || -> Result<_, failure::Error> { db::backup_schedule::find(p.get()?, uuid) }).await?
+            .ok_or_else(|| actix_web::error::ErrorNotFound("schedule not found"))?;
+
+        if !app::is_owner(&session, &Some(schedule.user_id)) {
+            log::warn!("Delete of backup schedule {} denied, not the owner", uuid);
+            return Err(actix_web::error::ErrorNotFound("schedule not found"));
+        }
+
+        let p = pool.get_ref().clone();
+
+        web::block(move || -> Result<(), failure::Error> {
+            db::backup_schedule::delete(p.get()?, uuid)
+        }).await?;
+
+        log::info!("Deleted backup schedule {}", uuid);
+
+        Ok(app::redirect(format!("{}/schedules/{}", renderer.base_path, uuid)))
     }
 }
 
+// Shared by the server and the daemon metrics listener, the queue depth is read from the db on every scrape
+pub async fn metrics(pool: web::Data<Pool>) -> Result<HttpResponse, actix_web::error::Error> {
+    let p = pool.get_ref().clone();
+
+    let body = web::block(move || -> Result<String, failure::Error> {
+        let counts = db::admin::count_by_status(p.get()?)?;
+        crate::metrics::set_queue_depth(&counts.into_iter().map(|(status, count)| (format!("{:?}", status), count)).collect::<Vec<_>>());
+        crate::metrics::render()
+    }).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
 #[actix_rt::main]
 pub async fn server(config: Config) -> std::io::Result<()> {
-    let tera = Tera::new("templates/**/*").unwrap();
+    let tera = assets::load_templates(config.templates_dir.as_deref()).expect("Could not load templates");
+    let static_overrides = assets::StaticOverrides(config.static_dir.clone());
 
     let base_uri = config.base_uri();
     let base_path = base_uri.path().trim_end_matches("/");
 
-    let spotify_oauth = build_spotify_oauth(&config.base_uri().to_string(), config.token_cache_path());
+    let default_scope = BackupOptions::new(Collection::all()).scope();
+    let spotify_oauth = build_spotify_oauth(config.client_id(), config.client_secret(), &config.base_uri().to_string(), config.token_cache_path(), &default_scope);
 
-    let downloads_path = config.downloads_path();
+    let store = build_store(&config).expect("Could not build backup store");
 
     // Start N db executor actors (N = number of cores avail)
     let manager = SqliteConnectionManager::file(config.db_path());
     let pool = Pool::new(manager).unwrap();
+    migrations::run(&pool).expect("Could not migrate database");
 
     let renderer = app::DefaultRenderer::new(base_path, tera);
 
-    HttpServer::new(move || {
+    // keys are checked by `Config::validate`
+    let keyring = crypto::keyring_from_config(&config).expect("Could not load token keys").map(Arc::new);
+
+    if keyring.is_none() {
+        log::warn!("token_key is not set, spotify tokens are saved unencrypted and recurring backups are disabled");
+    }
+
+    let retention = config.retention_limits();
+
+    let session_key = match config.session_key() {
+        // checked by `Config::validate`
+        Some(key) => base64::decode(key).expect("Could not parse session_key"),
+        None => {
+            log::warn!("session_key is not set, using a random key. Users are logged out when the server restarts");
+            crypto::random_bytes(32).expect("Could not generate session key")
+        }
+    };
+    let link_key = LinkKey::derive(&session_key);
+    let secure_cookies = base_uri.scheme_str() == Some("https");
+    let cookie_path = format!("{}/", base_path);
+
+    if config.admin_users.is_empty() {
+        log::info!("admin_users is not set, the admin section is disabled");
+    }
+
+    let admin_users = admin::AdminUsers::new(config.admin_users.clone());
+    let heartbeat_window = health::HeartbeatWindow(config.job_lease());
+
+    let tls = config.tls().map(load_tls).transpose()?;
+
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(middleware::Logger::new(HTTP_LOG_FORMAT))
+            .wrap(CookieSession::signed(&session_key)
+                .name("spotify-backup")
+                .path(&cookie_path)
+                .http_only(true)
+                .secure(secure_cookies)
+                // not sent with cross site posts, so other sites can't cancel backups or stop schedules
+                // for a logged in user. Not `Strict`, the session must come back with spotify's redirect to `/callback`
+                .same_site(SameSite::Lax))
+            // outermost, so the logger and handlers see the id
+            .wrap_fn(|mut req, srv| {
+                let id = http::HeaderValue::from_str(&request_id(&req)).expect("request ids are valid header values");
+                req.headers_mut().insert(http::HeaderName::from_static(REQUEST_ID_HEADER), id.clone());
+                let response = srv.call(req);
+
+                async move {
+                    let mut res = response.await?;
+                    res.headers_mut().insert(http::HeaderName::from_static(REQUEST_ID_HEADER), id);
+                    Ok(res)
+                }
+            })
             .data(pool.clone())
             .data(spotify_oauth.clone())
             .data(renderer.clone())
+            .data(keyring.clone())
+            .data(retention)
+            .data(store.clone())
+            .data(link_key.clone())
+            .data(admin_users.clone())
+            .data(heartbeat_window)
+            .data(static_overrides.clone())
             .route("/", web::get().to(app::index))
             .route("/callback", web::get().to(app::callback))
+            .route("/login", web::get().to(app::login))
+            .route("/logout", web::get().to(app::logout))
+            .route("/backups", web::get().to(app::history_get))
             .route("/backups/{id}", web::get().to(app::backup_get))
+            .route("/schedules/{id}", web::get().to(app::schedule_get))
+            .route("/admin", web::get().to(admin::index))
+            .route("/admin/backups/{id}/{action}", web::post().to(admin::backup_action))
             .service(
                 web::scope("/api")
                     .route("/backups/{id}", web::get().to(api::backup_get))
+                    .route("/backups/{id}/events", web::get().to(api::backup_events))
+                    .route("/backups/{id}/cancel", web::post().to(api::backup_cancel))
                     .route("/backups", web::post().to(api::backup_start))
+                    .route("/schedules/{id}/delete", web::post().to(api::schedule_delete))
+                    .route("/admin/backups", web::get().to(admin::api_backups))
+                    .route("/admin/backups/{id}/{action}", web::post().to(admin::api_backup_action))
             )
-            .service(actix_files::Files::new("/static", "static/"))
-            .service(actix_files::Files::new("/downloads", downloads_path.clone()))
-    })
-        .bind("0.0.0.0:8000")
-        .unwrap()
-        .run()
-        .await
+            .route("/static/{path:.*}", web::get().to(assets::static_file))
+            .route("/downloads/{name}", web::get().to(app::download))
+            .route("/metrics", web::get().to(metrics))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+    });
+
+    if let Some(workers) = config.http_workers {
+        server = server.workers(workers);
+    }
+
+    for addr in config.listen_addrs() {
+        server = match (addr, &tls) {
+            (ListenAddr::Tcp(addr), Some(tls)) => {
+                log::info!("Listening on https://{}", addr);
+                server.bind_rustls(addr, tls.clone())?
+            },
+            (ListenAddr::Tcp(addr), None) => {
+                log::info!("Listening on http://{}", addr);
+                server.bind(addr)?
+            },
+            (ListenAddr::Unix(path), _) => {
+                // left behind if the server did not stop cleanly, binding fails otherwise
+                if path.exists() {
+                    std::fs::remove_file(&path)?;
+                }
+                log::info!("Listening on unix:{}", path.display());
+                server.bind_uds(path)?
+            },
+        };
+    }
+
+    server.run().await
+}
+
+fn load_tls(tls: &TlsConfig) -> std::io::Result<rustls::ServerConfig> {
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let open = |path: &std::path::Path| std::fs::File::open(path)
+        .map(std::io::BufReader::new)
+        .map_err(|err| std::io::Error::new(err.kind(), format!("Could not open {:?}: {}", path, err)));
+
+    let cert_chain = certs(&mut open(&tls.cert_path)?)
+        .map_err(|_| invalid(format!("Could not parse certificates in {:?}", tls.cert_path)))?;
+
+    let mut keys = pkcs8_private_keys(&mut open(&tls.key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(&tls.key_path)?).unwrap_or_default();
+    }
+    let key = keys.into_iter().next()
+        .ok_or_else(|| invalid(format!("No private key found in {:?}", tls.key_path)))?;
+
+    let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    server_config.set_single_cert(cert_chain, key).map_err(|err| invalid(format!("Invalid certificate: {}", err)))?;
+    Ok(server_config)
+}
+
+// Encrypts every saved token with the current `token_key`, including tokens saved in plain text
+// or with one of the `old_token_keys`. Old keys can be removed from the config after this runs
+pub fn rotate_keys(config: Config) -> Result<(), failure::Error> {
+    let keyring = crypto::keyring_from_config(&config)?
+        .ok_or_else(|| failure::format_err!("token_key is not set"))?;
+
+    let manager = SqliteConnectionManager::file(config.db_path());
+    let pool = Pool::new(manager)?;
+    migrations::run(&pool)?;
+
+    let tokens = db::backup_request::rotate_tokens(pool.get()?, &keyring)?;
+    let refresh_tokens = db::backup_schedule::rotate_refresh_tokens(pool.get()?, &keyring)?;
+
+    log::info!("Encrypted {} tokens and {} refresh tokens with key {}", tokens.rotated, refresh_tokens.rotated, keyring.current_id());
+
+    // The rest were rotated, but the old keys are still needed until these expire or are deleted
+    if tokens.failed > 0 || refresh_tokens.failed > 0 {
+        failure::bail!("Could not open {} tokens and {} refresh tokens with any configured key, see the log", tokens.failed, refresh_tokens.failed)
+    }
+
+    Ok(())
+}
+
+// Shows the current schema version and pending migrations, applies them unless `status_only`
+pub fn migrate(config: Config, status_only: bool) -> Result<(), failure::Error> {
+    let mut c = if status_only {
+        rusqlite::Connection::open_with_flags(config.db_path(), rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?
+    } else {
+        rusqlite::Connection::open(config.db_path())?
+    };
+
+    let pending = migrations::pending(&c)?;
+    println!("Database {:?} is at version {}", config.db_path(), migrations::current_version(&c)?);
+
+    for migration in pending.iter() {
+        println!("Pending migration {} {}", migration.version, migration.name);
+    }
+
+    if !status_only {
+        let applied = migrations::migrate(&mut c)?;
+        println!("Applied {} migrations", applied.len());
+    }
+
+    Ok(())
 }
@@ -1,5 +1,5 @@
 use rspotify::spotify::client::Spotify;
-use rspotify::spotify::model::page::Page;
+use rspotify::spotify::model::page::{Page, CursorBasedPage};
 use rspotify::spotify::model::track::FullTrack;
 use rspotify::spotify::senum::AlbumType;
 use std::str::FromStr;
@@ -8,6 +8,7 @@ use rspotify::spotify::model::album::FullAlbum;
 use failure::Error;
 
 use crate::serialize::*;
+use crate::metrics;
 
 // TODO: Nothing in this file is tested at all, write it tests
 
@@ -23,7 +24,7 @@ pub trait SpotifyClient {
         user_id: &str,
         limit: Option<u32>,
         offset: Option<u32>,
-    ) -> Result<Page<PlaylistId>, Error>;
+    ) -> Result<Page<PlaylistSummary>, Error>;
 
     fn playlist(
         &self,
@@ -37,28 +38,89 @@ pub trait SpotifyClient {
         limit: Option<u32>,
         offset: Option<u32>,
     ) -> Result<Page<Track>, Error>;
+
+    fn saved_tracks(
+        &self,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Result<Page<Track>, Error>;
+
+    fn followed_artists(
+        &self,
+        limit: Option<u32>,
+        after: Option<String>,
+    ) -> Result<CursorBasedPage<Artist>, Error>;
+}
+
+// Shared by the tests of every collection
+#[cfg(test)]
+mockall::mock! {
+    pub SpotifyClient { }
+    trait SpotifyClient {
+        fn saved_albums(
+            &self,
+            limit: Option<u32>,
+            offset: Option<u32>
+        ) -> Result<Page<Album>, Error>;
+
+        fn playlists(
+            &self,
+            user_id: &str,
+            limit: Option<u32>,
+            offset: Option<u32>,
+        ) -> Result<Page<PlaylistSummary>, Error>;
+
+        fn playlist(
+            &self,
+            playlist_id: &PlaylistId,
+        ) -> Result<(Playlist, Page<Track>), Error>;
+
+        fn playlist_tracks(
+            &self,
+            user_id: &str,
+            playlist_id: &PlaylistId,
+            limit: Option<u32>,
+            offset: Option<u32>,
+        ) -> Result<Page<Track>, Error>;
+
+        fn saved_tracks(
+            &self,
+            limit: Option<u32>,
+            offset: Option<u32>,
+        ) -> Result<Page<Track>, Error>;
+
+        fn followed_artists(
+            &self,
+            limit: Option<u32>,
+            after: Option<String>,
+        ) -> Result<CursorBasedPage<Artist>, Error>;
+    }
 }
 
 
+// Every try is counted in `metrics::SPOTIFY_API_CALLS` under `endpoint`
 trait SpotifyApiRetry {
-    fn with_api_retry<F, T>(&self, max_tries: u32, f: F) -> Result<T, Error>
+    fn with_api_retry<F, T>(&self, endpoint: &str, max_tries: u32, f: F) -> Result<T, Error>
         where
             F: Fn(&Self) -> Result<T, Error>;
 }
 
 impl SpotifyApiRetry for Spotify {
-    fn with_api_retry<F, T>(&self, max_tries: u32, f: F) -> Result<T, Error>
+    fn with_api_retry<F, T>(&self, endpoint: &str, max_tries: u32, f: F) -> Result<T, Error>
         where
             F: Fn(&Spotify) -> Result<T, Error> {
         let result = f(&self);
+        metrics::observe_api_call(endpoint, &result);
 
         if let Err(ref err) = result {
             if let Some(rspotify::spotify::client::ApiError::RateLimited(Some(i))) = err.downcast_ref::<rspotify::spotify::client::ApiError>() {
-                std::thread::sleep(std::time::Duration::from_secs(*i as u64 * 2));
+                let wait = *i as u64 * 2;
+                std::thread::sleep(std::time::Duration::from_secs(wait));
+                metrics::RATE_LIMIT_WAITS.observe(wait as f64);
 
                 if max_tries > 0 {
                     log::warn!("rate limited, retrying api call after {} seconds", i);
-                    self.with_api_retry(max_tries - 1, f)
+                    self.with_api_retry(endpoint, max_tries - 1, f)
                 } else {
                     log::error!("rate limited, giving up");
                     result
@@ -78,7 +140,7 @@ impl SpotifyClient for Spotify {
         limit: Option<u32>,
         offset: Option<u32>,
     ) -> Result<Page<Album>, Error> {
-        self.current_user_saved_albums(limit, offset)
+        let result: Result<Page<Album>, Error> = self.current_user_saved_albums(limit, offset)
             .map(|page| {
                 let albums: Vec<Album> = page.items.iter().map(|i| i.album.clone().into()).collect();
 
@@ -92,7 +154,10 @@ impl SpotifyClient for Spotify {
                     next: page.next,
                 }
             })
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+
+        metrics::observe_api_call("saved_albums", &result);
+        result
     }
 
     fn playlists(
@@ -100,14 +165,19 @@ impl SpotifyClient for Spotify {
         user_id: &str,
         limit: Option<u32>,
         offset: Option<u32>,
-    ) -> Result<Page<PlaylistId>, Error> {
-        self.user_playlists(user_id, limit, offset)
+    ) -> Result<Page<PlaylistSummary>, Error> {
+        let result: Result<Page<PlaylistSummary>, Error> = self.user_playlists(user_id, limit, offset)
             .map(|page| {
-                let ids = page.items.iter().map(|p| p.id.clone()).collect();
+                let summaries = page.items.iter().map(|p| PlaylistSummary {
+                    id: p.id.clone(),
+                    name: p.name.clone(),
+                    owner_id: p.owner.id.clone(),
+                    collaborative: p.collaborative,
+                }).collect();
 
                 Page {
                     href: page.href,
-                    items: ids,
+                    items: summaries,
                     limit: page.limit,
                     offset: page.offset,
                     previous: page.previous,
@@ -115,7 +185,10 @@ impl SpotifyClient for Spotify {
                     next: page.next,
                 }
             })
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+
+        metrics::observe_api_call("playlists", &result);
+        result
     }
 
     fn playlist(
@@ -157,7 +230,7 @@ impl SpotifyClient for Spotify {
                 .map_err(|e| e.into())
         };
 
-        self.with_api_retry(3, f)
+        self.with_api_retry("playlist", 3, f)
     }
 
     fn playlist_tracks(
@@ -191,7 +264,59 @@ impl SpotifyClient for Spotify {
                 .map_err(|e| e.into())
         };
 
-        self.with_api_retry(3, f)
+        self.with_api_retry("playlist_tracks", 3, f)
+    }
+
+    fn saved_tracks(
+        &self,
+        limit: Option<u32>,
+        offset: Option<u32>,
+    ) -> Result<Page<Track>, Error> {
+        let f = |spotify: &Self| {
+            spotify.current_user_saved_tracks(limit, offset)
+                .map(|page| {
+                    let tracks: Vec<Track> = page.items.iter().map(|i| i.track.clone().into()).collect();
+
+                    Page {
+                        href: page.href,
+                        items: tracks,
+                        limit: page.limit,
+                        offset: page.offset,
+                        previous: page.previous,
+                        total: page.total,
+                        next: page.next,
+                    }
+                })
+                .map_err(|e| e.into())
+        };
+
+        self.with_api_retry("saved_tracks", 3, f)
+    }
+
+    fn followed_artists(
+        &self,
+        limit: Option<u32>,
+        after: Option<String>,
+    ) -> Result<CursorBasedPage<Artist>, Error> {
+        let f = |spotify: &Self| {
+            spotify.current_user_followed_artists(limit, after.clone())
+                .map(|followed| {
+                    let page = followed.artists;
+                    let artists: Vec<Artist> = page.items.iter().map(|a| Artist { name: a.name.clone() }).collect();
+
+                    CursorBasedPage {
+                        href: page.href,
+                        items: artists,
+                        limit: page.limit,
+                        next: page.next,
+                        cursors: page.cursors,
+                        total: page.total,
+                    }
+                })
+                .map_err(|e| e.into())
+        };
+
+        self.with_api_retry("followed_artists", 3, f)
     }
 }
 
@@ -1,22 +1,135 @@
-use rspotify::spotify::oauth2::SpotifyOAuth;
+use rspotify::spotify::client::Spotify;
+use rspotify::spotify::oauth2::{SpotifyClientCredentials, SpotifyOAuth, TokenInfo};
 use failure::Error;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::path::PathBuf;
 
 pub mod auth;
 pub mod client;
+pub mod pkce;
 
-pub fn build_spotify_oauth(base_url: &str, cache_path: PathBuf) -> SpotifyOAuth {
-    // Needs env file
+// Without `client_secret` users are authorized with PKCE, see `pkce`
+pub fn build_spotify_oauth(client_id: &str, client_secret: Option<&str>, base_url: &str, cache_path: PathBuf, scope: &str) -> SpotifyOAuth {
     SpotifyOAuth::default()
+        .client_id(client_id)
+        .client_secret(client_secret.unwrap_or_default())
         .redirect_uri(&format!("{}/callback", base_url.trim_end_matches("/")))
         .cache_path(cache_path)
-        .scope("user-library-read playlist-read-private") // TODO: Maybe needs more scopes?
+        .scope(scope)
         .build()
 }
 
-pub fn build_user_redirect_uri(oauth: &SpotifyOAuth) -> Result<String, Error> {
-    let state = rspotify::spotify::util::generate_random_string(16);
-    let auth_url = oauth.get_authorize_url(Some(&state), None);
+pub fn build_spotify_client(token_info: TokenInfo) -> Spotify {
+    let client_credential = SpotifyClientCredentials::default()
+        .token_info(token_info)
+        .build();
 
-    Ok(auth_url)
+    Spotify::default()
+        .client_credentials_manager(client_credential)
+        .build()
+}
+
+pub fn uses_pkce(oauth: &SpotifyOAuth) -> bool {
+    oauth.client_secret.is_empty()
+}
+
+pub struct AuthRedirect {
+    pub url: String,
+    // must be checked when spotify redirects back, otherwise anyone could send the user a callback url
+    pub state: String,
+    // only with PKCE, needed to get the token
+    pub verifier: Option<String>,
+}
+
+// Only asks the user for `scope`. `payload` is sent back to us by spotify in the `state`
+// param, after the random part
+pub fn build_user_redirect_uri<T: Serialize>(oauth: &SpotifyOAuth, scope: &str, payload: &T) -> Result<AuthRedirect, Error> {
+    let random = rspotify::spotify::util::generate_random_string(16);
+    let encoded_payload = base64::encode_config(&serde_json::to_vec(payload)?, base64::URL_SAFE_NO_PAD);
+    let state = format!("{}.{}", random, encoded_payload);
+
+    let oauth = oauth.clone().scope(scope);
+
+    if uses_pkce(&oauth) {
+        let verifier = pkce::new_verifier()?;
+        let url = pkce::authorize_url(&oauth, &state, &verifier);
+        Ok(AuthRedirect { url, state, verifier: Some(verifier) })
+    } else {
+        let url = oauth.get_authorize_url(Some(&state), None);
+        Ok(AuthRedirect { url, state, verifier: None })
+    }
+}
+
+// `verifier` is the one returned by `build_user_redirect_uri` when using PKCE
+pub fn get_access_token(oauth: &SpotifyOAuth, code: &str, verifier: Option<&str>) -> Result<TokenInfo, Error> {
+    match verifier {
+        Some(verifier) => pkce::get_access_token(oauth, code, verifier),
+        None => oauth.get_access_token(code).ok_or_else(|| failure::format_err!("spotify did not accept the authorization code")),
+    }
+}
+
+// Tokens are refreshed the same way they were requested, refresh tokens given to a PKCE
+// authorization don't work with the client secret and the other way around
+pub fn refresh_access_token(oauth: &SpotifyOAuth, refresh_token: &str) -> Option<TokenInfo> {
+    if !uses_pkce(oauth) {
+        return oauth.refresh_access_token(refresh_token);
+    }
+
+    match pkce::refresh_access_token(oauth, refresh_token) {
+        Ok(token) => Some(token),
+        Err(err) => {
+            log::warn!("Could not refresh spotify token: {}", err);
+            None
+        }
+    }
+}
+
+pub fn payload_from_state<T: DeserializeOwned>(state: &str) -> Result<T, Error> {
+    let encoded_payload = state
+        .splitn(2, '.')
+        .nth(1)
+        .ok_or_else(|| failure::format_err!("state does not include a payload"))?;
+
+    let json = base64::decode_config(encoded_payload, base64::URL_SAFE_NO_PAD)?;
+
+    serde_json::from_slice(&json).map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{BackupOptions, Collection};
+
+    #[test]
+    fn test_payload_is_sent_in_state() -> Result<(), Error> {
+        let options = BackupOptions::new(vec![Collection::LikedSongs, Collection::FollowedArtists]);
+        let oauth = build_spotify_oauth("id", Some("secret"), "http://localhost:8000", PathBuf::from("/tmp/token.json"), "user-library-read");
+
+        let redirect = build_user_redirect_uri(&oauth, &options.scope(), &options)?;
+        let (uri, state) = (redirect.url, redirect.state);
+
+        assert!(uri.contains("user-follow-read"));
+        assert!(uri.contains(&format!("state={}", state)));
+        assert!(!uri.contains("code_challenge"));
+        assert_eq!(redirect.verifier, None);
+        assert_eq!(payload_from_state::<BackupOptions>(&state)?, options);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uses_pkce_without_secret() -> Result<(), Error> {
+        let oauth = build_spotify_oauth("id", None, "http://localhost:8000", PathBuf::from("/tmp/token.json"), "user-library-read");
+
+        let redirect = build_user_redirect_uri(&oauth, "user-library-read", &())?;
+        let verifier = redirect.verifier.expect("pkce verifier");
+
+        assert!(redirect.url.contains(&format!("code_challenge_method=S256&code_challenge={}", pkce::challenge(&verifier))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_without_payload_is_an_error() {
+        assert!(payload_from_state::<BackupOptions>("abcdefghijklmnop").is_err());
+    }
 }
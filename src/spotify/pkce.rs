@@ -0,0 +1,80 @@
+// Authorization code flow with PKCE, see https://developer.spotify.com/documentation/general/guides/authorization-guide/
+// Used instead of the client secret when it is not set, tokens are requested with the client id and
+// the verifier created for each authorization. rspotify only supports the flow with a secret
+use failure::Error;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use rspotify::spotify::oauth2::{SpotifyOAuth, TokenInfo};
+
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+
+// 64 url safe characters, spotify accepts 43 to 128
+pub fn new_verifier() -> Result<String, Error> {
+    let mut bytes = [0u8; 48];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| failure::format_err!("Could not generate pkce verifier"))?;
+    Ok(base64::encode_config(&bytes[..], base64::URL_SAFE_NO_PAD))
+}
+
+pub fn challenge(verifier: &str) -> String {
+    base64::encode_config(digest::digest(&digest::SHA256, verifier.as_bytes()).as_ref(), base64::URL_SAFE_NO_PAD)
+}
+
+pub fn authorize_url(oauth: &SpotifyOAuth, state: &str, verifier: &str) -> String {
+    format!("{}&code_challenge_method=S256&code_challenge={}", oauth.get_authorize_url(Some(state), None), challenge(verifier))
+}
+
+fn request_token(params: &[(&str, &str)]) -> Result<TokenInfo, Error> {
+    let response = reqwest::blocking::Client::new()
+        .post(TOKEN_URL)
+        .form(params)
+        .send()?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().unwrap_or_default();
+        failure::bail!("spotify token request failed with {}: {}", status, body)
+    }
+
+    let mut token: TokenInfo = serde_json::from_str(&response.text()?)?;
+    token.expires_at = Some(time::get_time().sec + token.expires_in as i64);
+    Ok(token)
+}
+
+pub fn get_access_token(oauth: &SpotifyOAuth, code: &str, verifier: &str) -> Result<TokenInfo, Error> {
+    request_token(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &oauth.redirect_uri),
+        ("client_id", &oauth.client_id),
+        ("code_verifier", verifier),
+    ])
+}
+
+pub fn refresh_access_token(oauth: &SpotifyOAuth, refresh_token: &str) -> Result<TokenInfo, Error> {
+    request_token(&[
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", &oauth.client_id),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge() {
+        // Example from RFC 7636, appendix B
+        assert_eq!(challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn test_verifier_is_url_safe() -> Result<(), Error> {
+        let verifier = new_verifier()?;
+
+        assert_eq!(verifier.len(), 64);
+        assert!(verifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(verifier, new_verifier()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::serialize::*;
+use crate::spotify::client::*;
+use crate::progress::Progress;
+use failure::Error;
+
+pub fn backup_liked_songs(spotify: &dyn SpotifyClient, progress: &dyn Fn(&Progress) -> Result<(), Error>) -> Result<Vec<Track>, Error> {
+    let mut parsed_tracks = vec![];
+    let mut offset = 0;
+
+    loop {
+        let tracks = spotify.saved_tracks(Some(50), Some(offset))?;
+
+        parsed_tracks.extend(tracks.items);
+
+        progress(&Progress::new("liked_songs", parsed_tracks.len() as u32, tracks.total))?;
+
+        if tracks.next.is_none() {
+            break;
+        } else {
+            offset = tracks.offset + 50;
+        }
+    }
+
+    Ok(parsed_tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockall::predicate::*;
+    use rspotify::spotify::model::page::Page;
+    use crate::spotify::client::MockSpotifyClient;
+
+    fn new_track(name: &str) -> Track {
+        Track {
+            name: name.into(),
+            album: Album {
+                title: "Mensagem".into(),
+                artists: vec![Artist { name: "Fernando Pessoa".into() }],
+                album_type: None,
+                release_date: None,
+            },
+        }
+    }
+
+    fn new_page(items: Vec<Track>, offset: u32, next: Option<String>) -> Page<Track> {
+        Page {
+            href: "https://...".into(),
+            items,
+            limit: 50,
+            offset,
+            previous: None,
+            total: 2,
+            next,
+        }
+    }
+
+    #[test]
+    fn test_backup_liked_songs_multiple_pages() {
+        let mut mock = MockSpotifyClient::new();
+
+        mock.expect_saved_tracks()
+            .with(eq(Some(50)), eq(Some(0)))
+            .times(1)
+            .returning(|_, _| Ok(new_page(vec![new_track("Mar Português")], 0, Some("next".into()))));
+
+        mock.expect_saved_tracks()
+            .with(eq(Some(50)), eq(Some(50)))
+            .times(1)
+            .returning(|_, _| Ok(new_page(vec![new_track("O Infante")], 50, None)));
+
+        let tracks = backup_liked_songs(&mock, &|_| Ok(())).unwrap();
+
+        let names: Vec<&str> = tracks.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["Mar Português", "O Infante"]);
+    }
+}
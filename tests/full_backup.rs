@@ -3,6 +3,7 @@ extern crate spotify_backup;
 use rspotify::spotify::client::Spotify;
 use rspotify::spotify::oauth2::{SpotifyClientCredentials, SpotifyOAuth};
 use rspotify::spotify::util::get_token;
+use spotify_backup::options::BackupOptions;
 
 #[test]
 fn test_full_backup() {
@@ -20,7 +21,7 @@ fn test_full_backup() {
         .client_credentials_manager(client_credential)
         .build();
 
-    let backup = spotify_backup::backup_fn::DefaultBackup::full_backup("simaomm", &spotify).unwrap();
+    let backup = spotify_backup::backup_fn::DefaultBackup::full_backup("simaomm", &spotify, &BackupOptions::default(), &|_| Ok(())).unwrap();
 
     assert!(backup.albums.len() >= 389);
 }
@@ -0,0 +1,290 @@
+extern crate spotify_backup;
+
+use ring::{digest, hmac};
+use spotify_backup::config::S3Config;
+use spotify_backup::server::store::{BackupStore, NotFound, S3Store};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const STUB_ACCESS_KEY: &str = "stub-access-key";
+const STUB_SECRET_KEY: &str = "stub-secret-key";
+const STUB_REGION: &str = "us-east-1";
+
+// A minimal S3 api on a local port for `cargo test`. Objects are kept in memory by path and
+// requests not signed with `STUB_ACCESS_KEY` and `STUB_SECRET_KEY` are rejected with a 403
+struct StubS3 {
+    addr: SocketAddr,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+struct StubRequest {
+    method: String,
+    path: String,
+    query: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl StubS3 {
+    fn start() -> StubS3 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let objects = Arc::new(Mutex::new(HashMap::new()));
+        let shared = objects.clone();
+
+        // Not joined, it stops with the test process
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle(stream, &shared);
+            }
+        });
+
+        StubS3 { addr, objects }
+    }
+
+    fn config(&self, secret_key: &str) -> S3Config {
+        S3Config {
+            endpoint: format!("http://{}", self.addr),
+            bucket: "backups".to_owned(),
+            region: STUB_REGION.to_owned(),
+            access_key: STUB_ACCESS_KEY.to_owned(),
+            secret_key: secret_key.to_owned(),
+            presigned_downloads: true,
+        }
+    }
+}
+
+// One request per connection, responses are sent with `Connection: close`
+fn handle(stream: TcpStream, objects: &Mutex<HashMap<String, Vec<u8>>>) {
+    let req = match read_request(&stream) {
+        Ok(req) => req,
+        Err(_) => return,
+    };
+
+    let (status, body) = match verify(&req) {
+        Err(err) => ("403 Forbidden", format!("<Error><Code>SignatureDoesNotMatch</Code><Message>{}</Message></Error>", err).into_bytes()),
+        Ok(()) => {
+            let mut objects = objects.lock().unwrap();
+
+            match req.method.as_str() {
+                "PUT" => {
+                    objects.insert(req.path, req.body);
+                    ("200 OK", vec![])
+                },
+                "GET" => match objects.get(&req.path) {
+                    Some(object) => ("200 OK", object.clone()),
+                    None => ("404 Not Found", b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+                },
+                "DELETE" => {
+                    objects.remove(&req.path);
+                    ("204 No Content", vec![])
+                },
+                "HEAD" if req.path == "/backups" => ("200 OK", vec![]),
+                _ => ("405 Method Not Allowed", vec![]),
+            }
+        }
+    };
+
+    let mut stream = &stream;
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len())
+        .and_then(|_| stream.write_all(&body));
+}
+
+fn read_request(stream: &TcpStream) -> std::io::Result<StubRequest> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default();
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+
+    let mut headers = HashMap::new();
+
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(i) = header.find(':') {
+            headers.insert(header[..i].trim().to_lowercase(), header[i + 1..].trim().to_owned());
+        }
+    }
+
+    let len = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    Ok(StubRequest { method, path: path.to_owned(), query: query.to_owned(), headers, body })
+}
+
+// Checks the AWS signature version 4 of the `Authorization` header, or of the query for presigned urls
+fn verify(req: &StubRequest) -> Result<(), String> {
+    let header = |name: &str| req.headers.get(name).cloned().ok_or_else(|| format!("missing {} header", name));
+
+    if let Some(authorization) = req.headers.get("authorization") {
+        // AWS4-HMAC-SHA256 Credential=<access key>/<scope>, SignedHeaders=<names>, Signature=<hex>
+        let fields: HashMap<&str, &str> = authorization.trim_start_matches("AWS4-HMAC-SHA256 ")
+            .split(", ")
+            .filter_map(|field| {
+                let mut kv = field.splitn(2, '=');
+                Some((kv.next()?, kv.next()?))
+            })
+            .collect();
+
+        let field = |name: &str| fields.get(name).map(|v| v.to_string()).ok_or_else(|| format!("missing {} in authorization", name));
+
+        let payload_hash = header("x-amz-content-sha256")?;
+        if payload_hash != sha256_hex(&req.body) {
+            return Err("x-amz-content-sha256 does not match the body".to_owned());
+        }
+
+        let signed_headers = field("SignedHeaders")?;
+        let mut canonical_headers = String::new();
+
+        for name in signed_headers.split(';') {
+            canonical_headers.push_str(&format!("{}:{}\n", name, header(name)?));
+        }
+
+        let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}", req.method, req.path, canonical_headers, signed_headers, payload_hash);
+
+        check_signature(&field("Credential")?, &header("x-amz-date")?, &canonical_request, &field("Signature")?)
+    } else {
+        let mut params = vec![];
+        let mut signature = None;
+
+        for param in req.query.split('&') {
+            if let Some(value) = param.strip_prefix("X-Amz-Signature=") {
+                signature = Some(value.to_owned());
+            } else {
+                params.push(param);
+            }
+        }
+
+        let signature = signature.ok_or_else(|| "missing authorization header or signature".to_owned())?;
+        let param = |name: &str| params.iter()
+            .find(|p| p.starts_with(&format!("{}=", name)))
+            .map(|p| p[name.len() + 1..].replace("%2F", "/"))
+            .ok_or_else(|| format!("missing {} in query", name));
+
+        let canonical_request = format!("{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", req.method, req.path, params.join("&"), header("host")?);
+
+        check_signature(&param("X-Amz-Credential")?, &param("X-Amz-Date")?, &canonical_request, &signature)
+    }
+}
+
+fn check_signature(credential: &str, amz_date: &str, canonical_request: &str, signature: &str) -> Result<(), String> {
+    let mut credential = credential.splitn(2, '/');
+    let access_key = credential.next().unwrap_or_default();
+    let scope = credential.next().unwrap_or_default();
+
+    if access_key != STUB_ACCESS_KEY {
+        return Err(format!("unknown access key {}", access_key));
+    }
+
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+
+    let mut key = format!("AWS4{}", STUB_SECRET_KEY).into_bytes();
+
+    for part in scope.split('/') {
+        key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes()).as_ref().to_vec();
+    }
+
+    if hex(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), string_to_sign.as_bytes()).as_ref()) == signature {
+        Ok(())
+    } else {
+        Err("signature does not match".to_owned())
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn check_store(store: &S3Store) {
+    let key = format!("test-{}.json", uuid::Uuid::new_v4());
+
+    store.put(&key, b"{\"albums\": []}").unwrap();
+
+    let mut saved = String::new();
+    store.open(&key).unwrap().read_to_string(&mut saved).unwrap();
+    assert_eq!(saved, "{\"albums\": []}");
+
+    let url = store.download_url(&key, Duration::from_secs(60)).unwrap().unwrap();
+    let downloaded = reqwest::blocking::get(&url).unwrap().text().unwrap();
+    assert_eq!(downloaded, "{\"albums\": []}");
+
+    store.delete(&key).unwrap();
+    assert!(store.open(&key).err().unwrap().downcast_ref::<NotFound>().is_some());
+}
+
+#[test]
+fn test_s3_store_with_stub() {
+    let stub = StubS3::start();
+    let store = S3Store::new(stub.config(STUB_SECRET_KEY)).unwrap();
+
+    check_store(&store);
+
+    store.check_writable().unwrap();
+    assert!(stub.objects.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_s3_store_with_wrong_secret_key() {
+    let stub = StubS3::start();
+    let store = S3Store::new(stub.config("wrong-secret-key")).unwrap();
+
+    let err = store.put("backup.json", b"{}").unwrap_err();
+    assert!(format!("{}", err).contains("403"));
+    assert!(store.check_writable().is_err());
+    assert!(stub.objects.lock().unwrap().is_empty());
+
+    let url = store.download_url("backup.json", Duration::from_secs(60)).unwrap().unwrap();
+    assert_eq!(reqwest::blocking::get(&url).unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+}
+
+// Needs an S3 compatible server with an existing bucket, eg. minio:
+//
+//   docker run -p 9000:9000 -e MINIO_ACCESS_KEY=minio -e MINIO_SECRET_KEY=minio123 minio/minio server /data
+//
+// and S3_TEST_ENDPOINT, S3_TEST_BUCKET, S3_TEST_ACCESS_KEY and S3_TEST_SECRET_KEY set, then
+//
+//   cargo test --test s3_store -- --ignored
+fn test_config() -> S3Config {
+    let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} must be set to run the s3 tests", name));
+
+    S3Config {
+        endpoint: var("S3_TEST_ENDPOINT"),
+        bucket: var("S3_TEST_BUCKET"),
+        region: std::env::var("S3_TEST_REGION").unwrap_or("us-east-1".to_owned()),
+        access_key: var("S3_TEST_ACCESS_KEY"),
+        secret_key: var("S3_TEST_SECRET_KEY"),
+        presigned_downloads: true,
+    }
+}
+
+#[test]
+#[ignore = "needs an S3 compatible server, see test_config"]
+fn test_s3_store() {
+    let store = S3Store::new(test_config()).unwrap();
+
+    check_store(&store);
+}